@@ -1,18 +1,33 @@
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 use std::fmt;
 use std::fs;
+use std::str::FromStr;
 
 use evdev::KeyCode as Key;
 use indexmap::IndexMap;
-use serde;
-use serde::de::{Error, Visitor};
+use regex::Regex;
+use serde::de::{Error, MapAccess, Visitor, value};
 use serde::{Deserialize, Deserializer};
 
+use super::parser::combo_or_vec;
+use super::parser::one_or_vec;
+use super::parser::opt_combo_or_vec;
+use super::parser::opt_keys;
+use super::parser::opt_single_key;
+use super::parser::parse_custom_modifiers;
 use super::parser::parse_key_combo;
+use super::parser::parse_modifier_side;
+use super::parser::parse_modifiers;
 use super::parser::parse_modmap;
-use super::parser::string_or_vec;
+use super::parser::parse_single_key;
+use super::parser::parse_tap_hold;
+use crate::device::DeviceMatcher;
+use crate::window::FocusedWindow;
 
-#[derive(Debug, Clone)]
+/// A macro blocks all input while it runs, keep it short.
+const MAX_MACRO_DELAY: u64 = 1000;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
 pub enum Modifier {
     Shift,
     Control,
@@ -20,6 +35,64 @@ pub enum Modifier {
     Windows,
 }
 
+/// Which side a modifier is pressed on.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash)]
+pub enum Side {
+    /// Either side, or both
+    #[default]
+    Either,
+    Left,
+    Right,
+}
+
+impl Side {
+    /// Sides of a pressed modifier a binding may ask for, the specific one first.
+    fn candidates(self) -> &'static [Side] {
+        match self {
+            Side::Either => &[Side::Left, Side::Right, Side::Either],
+            Side::Left => &[Side::Left, Side::Either],
+            Side::Right => &[Side::Right, Side::Either],
+        }
+    }
+
+    fn prefix(self) -> &'static str {
+        match self {
+            Side::Either => "",
+            Side::Left => "L",
+            Side::Right => "R",
+        }
+    }
+}
+
+/// Side of each modifier, only meaningful for the pressed ones.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash)]
+pub struct Sides {
+    pub shift: Side,
+    pub control: Side,
+    pub alt: Side,
+    pub windows: Side,
+}
+
+impl Sides {
+    pub fn get(&self, modifier: Modifier) -> Side {
+        match modifier {
+            Modifier::Shift => self.shift,
+            Modifier::Control => self.control,
+            Modifier::Alt => self.alt,
+            Modifier::Windows => self.windows,
+        }
+    }
+
+    pub fn set(&mut self, modifier: Modifier, side: Side) {
+        match modifier {
+            Modifier::Shift => self.shift = side,
+            Modifier::Control => self.control = side,
+            Modifier::Alt => self.alt = side,
+            Modifier::Windows => self.windows = side,
+        }
+    }
+}
+
 #[derive(Clone, Debug, Eq, PartialEq, Hash)]
 pub struct KeyCombo {
     pub key: Key,
@@ -27,6 +100,70 @@ pub struct KeyCombo {
     pub control: bool,
     pub alt: bool,
     pub windows: bool,
+    pub sides: Sides,
+    /// Names of the user-declared modifiers, see `Config::modifiers`
+    pub custom: BTreeSet<String>,
+}
+
+impl KeyCombo {
+    pub fn is_pressed(&self, modifier: Modifier) -> bool {
+        match modifier {
+            Modifier::Shift => self.shift,
+            Modifier::Control => self.control,
+            Modifier::Alt => self.alt,
+            Modifier::Windows => self.windows,
+        }
+    }
+
+    /// The key combos of bindings this pressed key combo matches, the side-specific ones first.
+    pub fn candidates(&self) -> Vec<KeyCombo> {
+        let mut res = vec![KeyCombo {
+            sides: Sides::default(),
+            ..self.clone()
+        }];
+        for modifier in [
+            Modifier::Shift,
+            Modifier::Control,
+            Modifier::Alt,
+            Modifier::Windows,
+        ] {
+            if !self.is_pressed(modifier) {
+                continue;
+            }
+            res = res
+                .iter()
+                .flat_map(|combo| {
+                    self.sides.get(modifier).candidates().iter().map(|side| {
+                        let mut combo = combo.clone();
+                        combo.sides.set(modifier, *side);
+                        combo
+                    })
+                })
+                .collect();
+        }
+        res
+    }
+}
+
+impl fmt::Display for KeyCombo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.control {
+            write!(f, "{}Ctrl-", self.sides.control.prefix())?;
+        }
+        if self.alt {
+            write!(f, "{}Alt-", self.sides.alt.prefix())?;
+        }
+        if self.shift {
+            write!(f, "{}Shift-", self.sides.shift.prefix())?;
+        }
+        if self.windows {
+            write!(f, "{}Super-", self.sides.windows.prefix())?;
+        }
+        for name in self.custom.iter() {
+            write!(f, "{}-", name)?;
+        }
+        write!(f, "{:?}", self.key)
+    }
 }
 
 impl<'de> Deserialize<'de> for KeyCombo {
@@ -58,59 +195,357 @@ impl<'de> Deserialize<'de> for KeyCombo {
 #[derive(Debug, Clone)]
 pub enum Action {
     Remap(KeyCombo),
+    /// Key combos emitted in order, with a delay in milliseconds between them
+    Macro(Vec<KeyCombo>, u64),
     Shell(String),
+    /// Type the text
+    Type(String),
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Action::Remap(combo) => write!(f, "remap {}", combo),
+            Action::Macro(combos, _) => {
+                let combos: Vec<String> = combos.iter().map(|x| x.to_string()).collect();
+                write!(f, "macro {}", combos.join(" "))
+            }
+            Action::Shell(command) => write!(f, "shell {}", command),
+            Action::Type(text) => write!(f, "type {:?}", text),
+        }
+    }
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct KeyBinding {
-    #[serde(rename = "key")]
-    pub key_combo: KeyCombo,
+    /// A key combo, or a sequence of key combos, e.g. `[Ctrl-X, Ctrl-F]`
+    #[serde(rename = "key", default, deserialize_with = "combo_or_vec")]
+    pub key_combos: Vec<KeyCombo>,
+    /// Keys pressed together instead of `key`, e.g. `[J, K]`
+    #[serde(default, deserialize_with = "opt_keys")]
+    pub chord: Option<Vec<Key>>,
+    /// Fire on the 2nd (or 3rd...) tap of `key` in a row
+    pub taps: Option<usize>,
+    /// Keys typed after `options.leader_key`, e.g. `[W, S]`
+    #[serde(default, deserialize_with = "opt_combo_or_vec")]
+    pub leader: Option<Vec<KeyCombo>>,
     pub shell: Option<String>,
-    pub remap: Option<KeyCombo>,
-    #[allow(dead_code)]
+    /// A key combo, or a list of key combos emitted in order
+    #[serde(default, deserialize_with = "opt_combo_or_vec")]
+    pub remap: Option<Vec<KeyCombo>>,
+    /// Milliseconds to wait between the key combos of a `remap` list, input stalls meanwhile
+    pub delay: Option<u64>,
+    /// Text to type, non-ASCII characters go through `options.unicode_input`
+    #[serde(rename = "type")]
+    pub text: Option<String>,
+    /// When to fire, see `KeyBinding::trigger_on`
+    pub on: Option<TriggerOn>,
     pub desc: Option<String>,
 }
 
+/// Which event of the key fires a binding.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TriggerOn {
+    /// Once per physical press
+    Press,
+    /// When the key is released, instead of pressed
+    Release,
+    /// On press, and again on each autorepeat while held
+    Repeat,
+}
+
 impl KeyBinding {
+    /// A remap repeats like the key it replaces, everything else fires once.
+    pub fn trigger_on(&self) -> TriggerOn {
+        match self.on {
+            Some(on) => on,
+            None if self.remap.as_ref().is_some_and(|x| x.len() == 1) => TriggerOn::Repeat,
+            None => TriggerOn::Press,
+        }
+    }
+
     #[inline]
     pub fn get_action(&self) -> Action {
-        if self.shell.is_some() {
-            return Action::Shell(self.shell.as_ref().unwrap().to_string());
+        if let Some(shell) = &self.shell {
+            return Action::Shell(shell.to_string());
         }
-        if self.remap.is_some() {
-            return Action::Remap(self.remap.clone().unwrap());
+        if let Some(remap) = &self.remap {
+            if remap.len() == 1 && self.delay.is_none() {
+                return Action::Remap(remap[0].clone());
+            }
+            return Action::Macro(remap.clone(), self.delay.unwrap_or(0));
+        }
+        if let Some(text) = &self.text {
+            return Action::Type(text.to_string());
         }
         unreachable!();
     }
 }
 
+/// How to decide between tap and hold, before the timeout.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TapHoldStrategy {
+    /// Only the timeout decides, other keys are delayed until then.
+    #[default]
+    Timeout,
+    /// Hold if another key is pressed and released while the key is held.
+    PermissiveHold,
+    /// Hold as soon as another key is pressed.
+    HoldOnOtherKeyPress,
+}
+
+/// Dual-role key, e.g. Escape when tapped, Control when held.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TapHold {
+    #[serde(deserialize_with = "parse_single_key")]
+    pub tap: Key,
+    #[serde(deserialize_with = "parse_single_key")]
+    pub hold: Key,
+    /// In milliseconds
+    #[serde(default = "default_tap_hold_timeout")]
+    pub timeout: u64,
+    #[serde(default)]
+    pub strategy: TapHoldStrategy,
+}
+
+fn default_tap_hold_timeout() -> u64 {
+    200
+}
+
+/// Tapped modifiers apply to the next key only.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OneShot {
+    #[serde(deserialize_with = "parse_modifiers")]
+    pub modifiers: Vec<Modifier>,
+    /// In milliseconds, a tapped modifier is forgotten after this
+    #[serde(default = "default_one_shot_timeout")]
+    pub timeout: u64,
+}
+
+fn default_one_shot_timeout() -> u64 {
+    1000
+}
+
+/// An exact string, or a regex with the `regex:` prefix, e.g. `regex:.* - VIM$`.
+#[derive(Debug, Clone)]
+pub enum TextMatcher {
+    Exact(String),
+    Regex(Regex),
+}
+
+impl TextMatcher {
+    pub fn is_match(&self, text: &str) -> bool {
+        match self {
+            TextMatcher::Exact(s) => s == text,
+            TextMatcher::Regex(re) => re.is_match(text),
+        }
+    }
+}
+
+impl FromStr for TextMatcher {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input.strip_prefix("regex:") {
+            Some(re) => Regex::new(re)
+                .map(TextMatcher::Regex)
+                .map_err(|e| format!("invalid regex '{}': {}", re, e)),
+            None => Ok(TextMatcher::Exact(input.to_string())),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for TextMatcher {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(D::Error::custom)
+    }
+}
+
+/// Properties of a window, all the given ones have to match.
+#[derive(Debug, Clone, Default)]
+pub struct WindowMatcher {
+    pub class: Option<TextMatcher>,
+    pub instance: Option<TextMatcher>,
+    pub title: Option<TextMatcher>,
+    pub role: Option<TextMatcher>,
+}
+
+impl WindowMatcher {
+    pub fn is_match(&self, window: &FocusedWindow) -> bool {
+        let is_match = |matcher: &Option<TextMatcher>, text: &str| {
+            matcher.as_ref().is_none_or(|x| x.is_match(text))
+        };
+        is_match(&self.class, &window.class)
+            && is_match(&self.instance, &window.instance)
+            && is_match(&self.title, &window.title)
+            && is_match(&self.role, &window.role)
+    }
+}
+
+/// Accepts a plain string as the class, e.g. `Alacritty`, or the map of properties.
+impl<'de> Deserialize<'de> for WindowMatcher {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct Fields {
+            class: Option<TextMatcher>,
+            instance: Option<TextMatcher>,
+            title: Option<TextMatcher>,
+            role: Option<TextMatcher>,
+        }
+
+        struct WindowMatcherVisitor;
+
+        impl<'de> Visitor<'de> for WindowMatcherVisitor {
+            type Value = WindowMatcher;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("class name, or map of class, instance, title and role")
+            }
+
+            fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
+            where
+                E: Error,
+            {
+                Ok(WindowMatcher {
+                    class: Some(value.parse().map_err(E::custom)?),
+                    ..Default::default()
+                })
+            }
+
+            fn visit_map<M>(self, map: M) -> Result<Self::Value, M::Error>
+            where
+                M: MapAccess<'de>,
+            {
+                let fields = Fields::deserialize(value::MapAccessDeserializer::new(map))?;
+                Ok(WindowMatcher {
+                    class: fields.class,
+                    instance: fields.instance,
+                    title: fields.title,
+                    role: fields.role,
+                })
+            }
+        }
+
+        deserializer.deserialize_any(WindowMatcherVisitor)
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct Group {
     pub key_bindings: Vec<KeyBinding>,
-    #[serde(default, deserialize_with = "string_or_vec", rename = "in")]
-    pub in_: Option<Vec<String>>,
-    #[serde(default, deserialize_with = "string_or_vec", rename = "notin")]
-    pub not_in: Option<Vec<String>>,
+    #[serde(default, deserialize_with = "one_or_vec", rename = "in")]
+    pub in_: Option<Vec<WindowMatcher>>,
+    #[serde(default, deserialize_with = "one_or_vec", rename = "notin")]
+    pub not_in: Option<Vec<WindowMatcher>>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct Mode {
     pub groups: Vec<String>,
     pub switch_key: Option<KeyCombo>,
+    /// The mode is active only while this key is held, like a layer
+    #[serde(default, deserialize_with = "opt_single_key")]
+    pub hold_key: Option<Key>,
+    /// Switch to the mode while one of these windows is focused
+    #[serde(default, deserialize_with = "one_or_vec")]
+    pub auto_when: Option<Vec<WindowMatcher>>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct Options {
     pub mode_switch_key: Option<KeyCombo>,
     pub default_mode: Option<String>,
+    /// In milliseconds, a pending key sequence is cancelled after this
+    #[serde(default = "default_sequence_timeout")]
+    pub sequence_timeout: u64,
+    #[serde(default)]
+    pub unicode_input: UnicodeInput,
+    #[serde(default)]
+    pub ascii_input: AsciiInput,
+    /// In milliseconds, all keys of a chord have to be pressed within this
+    #[serde(default = "default_chord_timeout")]
+    pub chord_timeout: u64,
+    /// In milliseconds, the longest pause between the taps of a multi-tap
+    #[serde(default = "default_tap_interval")]
+    pub tap_interval: u64,
+    /// Starts the `leader` bindings
+    pub leader_key: Option<KeyCombo>,
+    /// In milliseconds, waiting for the next key after the leader key
+    #[serde(default = "default_leader_timeout")]
+    pub leader_timeout: u64,
+    /// Show the keys that may follow in a notification
+    #[serde(default = "default_leader_hint")]
+    pub leader_hint: bool,
+}
+
+fn default_leader_timeout() -> u64 {
+    2000
+}
+
+fn default_leader_hint() -> bool {
+    true
+}
+
+fn default_tap_interval() -> u64 {
+    300
+}
+
+fn default_chord_timeout() -> u64 {
+    50
+}
+
+/// How `type` enters ASCII characters.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AsciiInput {
+    /// Press their keys on a US layout.
+    #[default]
+    Us,
+    /// Like other characters, through `unicode_input`. For layouts other than US.
+    Unicode,
+}
+
+/// How to type characters that have no key on the layout.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnicodeInput {
+    /// Ctrl-Shift-U, the hex code point, then Space. Understood by GTK and IBus.
+    #[default]
+    CtrlShiftU,
+    /// Skip them with a warning.
+    Ignore,
+}
+
+fn default_sequence_timeout() -> u64 {
+    1000
 }
 
 #[derive(Debug, Clone, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct Config {
+    #[serde(default)]
+    pub devices: Option<Vec<DeviceMatcher>>,
     #[serde(default, deserialize_with = "parse_modmap")]
-    #[allow(dead_code)]
     pub modmap: Option<HashMap<Key, Key>>,
+    #[serde(default, deserialize_with = "parse_tap_hold")]
+    pub tap_hold: Option<HashMap<Key, TapHold>>,
+    #[serde(default)]
+    pub one_shot: Option<OneShot>,
+    /// User-declared modifiers, e.g. `hyper: [KEY_CAPSLOCK]`. Their keys are never sent.
+    #[serde(default, deserialize_with = "parse_custom_modifiers")]
+    pub modifiers: Option<IndexMap<String, Vec<Key>>>,
     pub modes: Option<IndexMap<String, Mode>>,
     pub groups: IndexMap<String, Group>,
     pub options: Option<Options>,
@@ -118,8 +553,201 @@ pub struct Config {
 
 impl Config {
     pub fn load_from_file(filename: &str) -> Result<Self, Box<dyn std::error::Error>> {
-        let yaml = fs::read_to_string(&filename)?;
+        let yaml = fs::read_to_string(filename)?;
         let config: Config = serde_yaml::from_str(&yaml)?;
-        return Ok(config);
+        config.check_bindings()?;
+        config.check_modifiers()?;
+        Ok(config)
+    }
+
+    pub fn leader_key(&self) -> Option<&KeyCombo> {
+        self.options.as_ref().and_then(|x| x.leader_key.as_ref())
+    }
+
+    /// Whether any binding types text.
+    pub fn has_type_bindings(&self) -> bool {
+        self.groups
+            .values()
+            .flat_map(|x| x.key_bindings.iter())
+            .any(|x| x.text.is_some())
+    }
+
+    /// Whether any group or mode depends on the focused window.
+    pub fn has_window_conditions(&self) -> bool {
+        let groups = self.groups.values().any(|x| {
+            x.in_.as_ref().is_some_and(|x| !x.is_empty())
+                || x.not_in.as_ref().is_some_and(|x| !x.is_empty())
+        });
+        let modes = self
+            .modes
+            .iter()
+            .flat_map(|x| x.values())
+            .any(|x| x.auto_when.as_ref().is_some_and(|x| !x.is_empty()));
+        groups || modes
+    }
+
+    /// Every binding needs exactly one trigger and exactly one action.
+    fn check_bindings(&self) -> Result<(), Box<dyn std::error::Error>> {
+        for (name, group) in self.groups.iter() {
+            for kb in group.key_bindings.iter() {
+                let triggers = [
+                    !kb.key_combos.is_empty(),
+                    kb.chord.is_some(),
+                    kb.leader.is_some(),
+                ];
+                if triggers.iter().filter(|x| **x).count() != 1 {
+                    return Err(format!(
+                        "a binding of group {} needs exactly one of key, chord and leader",
+                        name
+                    )
+                    .into());
+                }
+                let actions = [kb.shell.is_some(), kb.remap.is_some(), kb.text.is_some()];
+                if actions.iter().filter(|x| **x).count() != 1 {
+                    return Err(format!(
+                        "a binding of group {} needs exactly one of shell, remap and type",
+                        name
+                    )
+                    .into());
+                }
+                if let Some(chord) = &kb.chord
+                    && chord.len() < 2
+                {
+                    return Err(format!("a chord of group {} needs two keys", name).into());
+                }
+                if kb.leader.is_some() && self.leader_key().is_none() {
+                    return Err(format!(
+                        "leader bindings of group {} need options.leader_key",
+                        name
+                    )
+                    .into());
+                }
+                if kb.delay.is_some_and(|x| x > MAX_MACRO_DELAY) {
+                    return Err(format!(
+                        "delay of group {} is at most {} milliseconds",
+                        name, MAX_MACRO_DELAY
+                    )
+                    .into());
+                }
+                if kb.taps.is_some() && kb.key_combos.len() != 1 {
+                    return Err(format!(
+                        "taps of group {} needs a single key combo, not a sequence or chord",
+                        name
+                    )
+                    .into());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Key combos are parsed before the `modifiers` section is known, check the names here.
+    fn check_modifiers(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let declared = self.modifiers.clone().unwrap_or_default();
+        for (name, keys) in declared.iter() {
+            if parse_modifier_side(name).is_some() {
+                return Err(format!("modifier {} is built-in, choose another name", name).into());
+            }
+            for key in keys.iter() {
+                if declared.iter().any(|(x, ks)| x != name && ks.contains(key)) {
+                    return Err(format!("{:?} belongs to more than one modifier", key).into());
+                }
+            }
+        }
+
+        let mut triggers: Vec<&KeyCombo> = vec![];
+        let mut outputs: Vec<&KeyCombo> = vec![];
+        for group in self.groups.values() {
+            for kb in group.key_bindings.iter() {
+                triggers.extend(kb.key_combos.iter());
+                triggers.extend(kb.leader.iter().flatten());
+                outputs.extend(kb.remap.iter().flatten());
+            }
+        }
+        for mode in self.modes.iter().flat_map(|x| x.values()) {
+            triggers.extend(mode.switch_key.iter());
+        }
+        triggers.extend(self.options.iter().flat_map(|x| x.mode_switch_key.iter()));
+        triggers.extend(self.leader_key());
+
+        for combo in triggers.iter() {
+            if let Some(name) = combo.custom.iter().find(|x| !declared.contains_key(*x)) {
+                return Err(format!("unknown modifier: {} in {}", name, combo).into());
+            }
+        }
+        for combo in outputs.iter() {
+            if !combo.custom.is_empty() {
+                return Err(format!("{} can't be sent, it has a custom modifier", combo).into());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(yaml: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let config: Config = serde_yaml::from_str(yaml)?;
+        config.check_bindings()?;
+        config.check_modifiers()
+    }
+
+    fn combo(input: &str) -> KeyCombo {
+        parse_key_combo(input).unwrap()
+    }
+
+    #[test]
+    fn side_specific_binding_wins() {
+        // Held on one side, the binding for that side comes before the one for either.
+        assert_eq!(
+            combo("LCtrl-X").candidates(),
+            vec![combo("LCtrl-X"), combo("Ctrl-X")]
+        );
+        assert_eq!(
+            combo("RCtrl-X").candidates(),
+            vec![combo("RCtrl-X"), combo("Ctrl-X")]
+        );
+        assert!(!combo("RCtrl-X").candidates().contains(&combo("LCtrl-X")));
+    }
+
+    #[test]
+    fn both_sides_held() {
+        // `current_combo` gives Either when both sides are held, any binding matches.
+        assert_eq!(
+            combo("Ctrl-X").candidates(),
+            vec![combo("LCtrl-X"), combo("RCtrl-X"), combo("Ctrl-X")]
+        );
+        let candidates = combo("LCtrl-RShift-X").candidates();
+        assert_eq!(candidates.first(), Some(&combo("LCtrl-RShift-X")));
+        assert_eq!(candidates.last(), Some(&combo("Ctrl-Shift-X")));
+        assert_eq!(candidates.len(), 4);
+    }
+
+    #[test]
+    fn binding_needs_one_action() {
+        let missing = "groups: {g: {key_bindings: [{key: Alt-C}]}}";
+        assert!(check(missing).is_err());
+        let both = "groups: {g: {key_bindings: [{key: Alt-C, remap: Ctrl-C, shell: ls}]}}";
+        assert!(check(both).is_err());
+        let one = "groups: {g: {key_bindings: [{key: Alt-C, remap: Ctrl-C}]}}";
+        assert!(check(one).is_ok());
+    }
+
+    #[test]
+    fn macro_delay_is_limited() {
+        let slow = "groups: {g: {key_bindings: [{key: Alt-C, remap: [A, B], delay: 5000}]}}";
+        assert!(check(slow).is_err());
+        let fast = "groups: {g: {key_bindings: [{key: Alt-C, remap: [A, B], delay: 20}]}}";
+        assert!(check(fast).is_ok());
+    }
+
+    #[test]
+    fn side_alias_is_rejected() {
+        let alias = "groups: {g: {key_bindings: [{key: RC-X, remap: Ctrl-C}]}}";
+        assert!(check(alias).is_err());
+        let side = "groups: {g: {key_bindings: [{key: RCtrl-X, remap: LCtrl-C}]}}";
+        assert!(check(side).is_ok());
     }
 }
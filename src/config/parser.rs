@@ -1,47 +1,108 @@
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 use std::fmt;
+use std::marker::PhantomData;
 use std::str::FromStr;
 
 use evdev::KeyCode as Key;
+use indexmap::IndexMap;
 use serde::de::{Error, MapAccess, SeqAccess, Visitor, value};
 use serde::{Deserialize, Deserializer};
 
 use super::config::KeyCombo;
 use super::config::Modifier;
+use super::config::Side;
+use super::config::Sides;
+use super::config::TapHold;
 
 // Some parse utils for serde-yaml
 
-pub fn string_or_vec<'de, D>(deserializer: D) -> Result<Option<Vec<String>>, D::Error>
+/// A single item, or a list of them.
+pub fn one_or_vec<'de, D, T>(deserializer: D) -> Result<Option<Vec<T>>, D::Error>
 where
     D: Deserializer<'de>,
+    T: Deserialize<'de>,
 {
-    struct StringOrVec;
+    struct OneOrVec<T>(PhantomData<T>);
 
-    impl<'de> Visitor<'de> for StringOrVec {
-        type Value = Option<Vec<String>>;
+    impl<'de, T> Visitor<'de> for OneOrVec<T>
+    where
+        T: Deserialize<'de>,
+    {
+        type Value = Option<Vec<T>>;
 
         fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-            formatter.write_str("string or list of strings")
+            formatter.write_str("one item or list of items")
         }
 
         fn visit_str<E>(self, s: &str) -> Result<Self::Value, E>
         where
             E: Error,
         {
-            Ok(Some(vec![s.to_owned()]))
+            let item = T::deserialize(value::StrDeserializer::new(s))?;
+            Ok(Some(vec![item]))
+        }
+
+        fn visit_map<M>(self, map: M) -> Result<Self::Value, M::Error>
+        where
+            M: MapAccess<'de>,
+        {
+            let item = T::deserialize(value::MapAccessDeserializer::new(map))?;
+            Ok(Some(vec![item]))
         }
 
         fn visit_seq<S>(self, seq: S) -> Result<Self::Value, S::Error>
         where
             S: SeqAccess<'de>,
         {
-            let result: Vec<String> =
-                Deserialize::deserialize(value::SeqAccessDeserializer::new(seq))?;
+            let result: Vec<T> = Deserialize::deserialize(value::SeqAccessDeserializer::new(seq))?;
             Ok(Some(result))
         }
     }
 
-    deserializer.deserialize_any(StringOrVec)
+    deserializer.deserialize_any(OneOrVec(PhantomData))
+}
+
+pub fn combo_or_vec<'de, D>(deserializer: D) -> Result<Vec<KeyCombo>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct ComboOrVec;
+
+    impl<'de> Visitor<'de> for ComboOrVec {
+        type Value = Vec<KeyCombo>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("key combo or list of key combos")
+        }
+
+        fn visit_str<E>(self, s: &str) -> Result<Self::Value, E>
+        where
+            E: Error,
+        {
+            Ok(vec![parse_key_combo(s).map_err(E::custom)?])
+        }
+
+        fn visit_seq<S>(self, seq: S) -> Result<Self::Value, S::Error>
+        where
+            S: SeqAccess<'de>,
+        {
+            let result: Vec<KeyCombo> =
+                Deserialize::deserialize(value::SeqAccessDeserializer::new(seq))?;
+            if result.is_empty() {
+                return Err(S::Error::custom("empty key sequence"));
+            }
+            Ok(result)
+        }
+    }
+
+    deserializer.deserialize_any(ComboOrVec)
+}
+
+pub fn opt_combo_or_vec<'de, D>(deserializer: D) -> Result<Option<Vec<KeyCombo>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    combo_or_vec(deserializer).map(Some)
 }
 
 pub fn parse_key(input: &str) -> Result<Key, Box<dyn std::error::Error>> {
@@ -105,8 +166,8 @@ where
             let mut modmap = HashMap::new();
 
             for (from, to) in remap.iter() {
-                let from_key = parse_key(&from).map_err(M::Error::custom)?;
-                let to_key = parse_key(&to).map_err(M::Error::custom)?;
+                let from_key = parse_key(from).map_err(M::Error::custom)?;
+                let to_key = parse_key(to).map_err(M::Error::custom)?;
                 modmap.insert(from_key, to_key);
             }
 
@@ -117,6 +178,65 @@ where
     deserializer.deserialize_any(ModmapRemap)
 }
 
+pub fn parse_single_key<'de, D>(deserializer: D) -> Result<Key, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let name = String::deserialize(deserializer)?;
+    parse_key(&name).map_err(D::Error::custom)
+}
+
+pub fn opt_keys<'de, D>(deserializer: D) -> Result<Option<Vec<Key>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let names: Vec<String> = Deserialize::deserialize(deserializer)?;
+    names
+        .iter()
+        .map(|x| parse_key(x).map_err(D::Error::custom))
+        .collect::<Result<Vec<Key>, D::Error>>()
+        .map(Some)
+}
+
+pub fn opt_single_key<'de, D>(deserializer: D) -> Result<Option<Key>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    parse_single_key(deserializer).map(Some)
+}
+
+pub fn parse_tap_hold<'de, D>(deserializer: D) -> Result<Option<HashMap<Key, TapHold>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let raw: HashMap<String, TapHold> = Deserialize::deserialize(deserializer)?;
+    let mut tap_hold = HashMap::new();
+    for (name, value) in raw.into_iter() {
+        let key = parse_key(&name).map_err(D::Error::custom)?;
+        tap_hold.insert(key, value);
+    }
+    Ok(Some(tap_hold))
+}
+
+pub fn parse_custom_modifiers<'de, D>(
+    deserializer: D,
+) -> Result<Option<IndexMap<String, Vec<Key>>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let raw: IndexMap<String, Vec<String>> = Deserialize::deserialize(deserializer)?;
+    let mut modifiers = IndexMap::new();
+    for (name, keys) in raw.into_iter() {
+        let keys = keys
+            .iter()
+            .map(|x| parse_key(x))
+            .collect::<Result<Vec<Key>, _>>()
+            .map_err(D::Error::custom)?;
+        modifiers.insert(name.to_lowercase(), keys);
+    }
+    Ok(Some(modifiers))
+}
+
 pub fn parse_key_combo(input: &str) -> Result<KeyCombo, Box<dyn std::error::Error>> {
     let keys: Vec<&str> = input.split("-").collect();
     if let Some((key, modifiers)) = keys.split_last() {
@@ -124,17 +244,26 @@ pub fn parse_key_combo(input: &str) -> Result<KeyCombo, Box<dyn std::error::Erro
         let mut control = false;
         let mut alt = false;
         let mut windows = false;
+        let mut sides = Sides::default();
+        let mut custom = BTreeSet::new();
 
         for modifier in modifiers.iter() {
-            match parse_modifier(modifier) {
-                Some(Modifier::Shift) => shift = true,
-                Some(Modifier::Control) => control = true,
-                Some(Modifier::Alt) => alt = true,
-                Some(Modifier::Windows) => windows = true,
-                None => {
+            let Some((modifier_, side)) = parse_modifier_side(modifier) else {
+                // Checked against the `modifiers` section after loading
+                if modifier.is_empty() || !modifier.chars().all(|c| c.is_alphanumeric() || c == '_')
+                {
                     return Err(format!("unknown modifier: {}", modifier).into());
                 }
+                custom.insert(modifier.to_lowercase());
+                continue;
+            };
+            match modifier_ {
+                Modifier::Shift => shift = true,
+                Modifier::Control => control = true,
+                Modifier::Alt => alt = true,
+                Modifier::Windows => windows = true,
             }
+            sides.set(modifier_, side);
         }
 
         Ok(KeyCombo {
@@ -143,12 +272,46 @@ pub fn parse_key_combo(input: &str) -> Result<KeyCombo, Box<dyn std::error::Erro
             control,
             alt,
             windows,
+            sides,
+            custom,
         })
     } else {
         Err(format!("empty key_press: {}", input).into())
     }
 }
 
+pub fn parse_modifiers<'de, D>(deserializer: D) -> Result<Vec<Modifier>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let names: Vec<String> = Deserialize::deserialize(deserializer)?;
+    names
+        .iter()
+        .map(|name| {
+            parse_modifier(name)
+                .ok_or_else(|| D::Error::custom(format!("unknown modifier: {}", name)))
+        })
+        .collect()
+}
+
+/// A modifier with an optional side, e.g. `Shift`, `LShift`, `RAlt`.
+pub fn parse_modifier_side(modifier: &str) -> Option<(Modifier, Side)> {
+    if let Some(res) = parse_modifier(modifier) {
+        return Some((res, Side::Either));
+    }
+    let name = modifier.to_uppercase();
+    let (side, rest) = match name.split_at_checked(1)? {
+        ("L", rest) => (Side::Left, rest),
+        ("R", rest) => (Side::Right, rest),
+        _ => return None,
+    };
+    // Not the one-letter aliases, `RC` reads like a typo
+    if rest.len() < 2 {
+        return None;
+    }
+    parse_modifier(rest).map(|res| (res, side))
+}
+
 pub fn parse_modifier(modifier: &str) -> Option<Modifier> {
     match &modifier.to_uppercase()[..] {
         // Shift
@@ -168,3 +331,35 @@ pub fn parse_modifier(modifier: &str) -> Option<Modifier> {
         _ => None,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn modifier_sides() {
+        assert_eq!(
+            parse_modifier_side("Ctrl"),
+            Some((Modifier::Control, Side::Either))
+        );
+        assert_eq!(
+            parse_modifier_side("LCtrl"),
+            Some((Modifier::Control, Side::Left))
+        );
+        assert_eq!(
+            parse_modifier_side("rshift"),
+            Some((Modifier::Shift, Side::Right))
+        );
+        // The one-letter aliases only come without a side.
+        assert_eq!(
+            parse_modifier_side("C"),
+            Some((Modifier::Control, Side::Either))
+        );
+        assert_eq!(parse_modifier_side("RC"), None);
+        assert_eq!(parse_modifier_side("LM"), None);
+        // Then it's a custom modifier, rejected unless declared in `modifiers`.
+        let combo = parse_key_combo("RC-X").unwrap();
+        assert!(!combo.control);
+        assert!(combo.custom.contains("rc"));
+    }
+}
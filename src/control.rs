@@ -0,0 +1,157 @@
+use std::error::Error;
+use std::fs;
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use log::{debug, warn};
+use nix::unistd::Uid;
+
+// A tiny line protocol over a Unix domain socket, one request per connection.
+//
+// Request:  a single line, e.g. `mode normal\n`
+// Response: `ok\n` followed by the payload, or `err <message>\n`
+
+/// A slow client must not block the event loop for long.
+const CLIENT_TIMEOUT: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Request {
+    /// Query the current mode
+    GetMode,
+    /// Switch to the given mode
+    SetMode(String),
+    /// Reload the config file
+    Reload,
+    /// Pass all keys through untouched
+    Pause,
+    /// Undo `Pause`
+    Resume,
+    /// List the key bindings of the current mode
+    Bindings,
+}
+
+impl Request {
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words[..] {
+            ["mode"] => Ok(Request::GetMode),
+            ["mode", name] => Ok(Request::SetMode(name.to_string())),
+            ["reload"] => Ok(Request::Reload),
+            ["pause"] => Ok(Request::Pause),
+            ["resume"] => Ok(Request::Resume),
+            ["bindings"] => Ok(Request::Bindings),
+            _ => Err(format!(
+                "unknown command '{}', expect one of: mode [NAME], reload, pause, resume, bindings",
+                line.trim()
+            )),
+        }
+    }
+}
+
+pub fn default_socket_path() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir).join("xburner.sock"),
+        None => PathBuf::from(format!("/tmp/xburner-{}.sock", Uid::current())),
+    }
+}
+
+/// Server side, served by the event loop.
+pub struct ControlServer {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl ControlServer {
+    pub fn bind(path: PathBuf) -> Result<Self, Box<dyn Error>> {
+        if path.exists() {
+            // Someone is still listening, don't steal the socket.
+            if UnixStream::connect(&path).is_ok() {
+                return Err(format!("{} is in use, is XBurner running?", path.display()).into());
+            }
+            fs::remove_file(&path)?;
+        }
+        let listener = UnixListener::bind(&path)?;
+        listener.set_nonblocking(true)?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+        Ok(Self { listener, path })
+    }
+
+    pub fn listener(&self) -> &UnixListener {
+        &self.listener
+    }
+
+    /// Accept all pending clients, `handle` turns a request into the response payload.
+    pub fn serve<F>(&self, mut handle: F)
+    where
+        F: FnMut(Request) -> Result<String, Box<dyn Error>>,
+    {
+        loop {
+            let stream = match self.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    warn!("Failed to accept control client: {}", e);
+                    return;
+                }
+            };
+            if let Err(e) = Self::serve_client(stream, &mut handle) {
+                debug!("Control client error: {}", e);
+            }
+        }
+    }
+
+    fn serve_client<F>(mut stream: UnixStream, handle: &mut F) -> Result<(), Box<dyn Error>>
+    where
+        F: FnMut(Request) -> Result<String, Box<dyn Error>>,
+    {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+        stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+
+        let mut line = String::new();
+        BufReader::new(&stream).read_line(&mut line)?;
+        debug!("Control request => {:?}", line);
+
+        let response = match Request::parse(&line) {
+            Ok(request) => match handle(request) {
+                Ok(payload) => format!("ok\n{}", payload),
+                Err(e) => format!("err {}\n", e),
+            },
+            Err(e) => format!("err {}\n", e),
+        };
+        stream.write_all(response.as_bytes())?;
+        Ok(())
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        fs::remove_file(&self.path).ok();
+    }
+}
+
+/// Client side, used by `ctl`. Returns the payload of the response.
+pub fn send_request(path: &Path, line: &str) -> Result<String, Box<dyn Error>> {
+    let mut stream = UnixStream::connect(path).map_err(|e| {
+        format!(
+            "Failed to connect {}: {}, is XBurner running?",
+            path.display(),
+            e
+        )
+    })?;
+    stream.write_all(format!("{}\n", line.trim()).as_bytes())?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    if let Some(payload) = response.strip_prefix("ok\n") {
+        return Ok(payload.to_string());
+    }
+    match response.strip_prefix("err ") {
+        Some(message) => Err(message.trim_end().to_string().into()),
+        None => Err(format!("Bad response: {:?}", response).into()),
+    }
+}
@@ -1,36 +1,460 @@
 use std::collections::HashMap;
 use std::error::Error;
+use std::fmt;
 use std::fs::read_dir;
 use std::os::unix::ffi::OsStrExt;
+use std::str::FromStr;
 
 use evdev::Device;
+use log::{debug, info, warn};
+use regex::Regex;
+use serde::de::{self, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+
+use crate::NAME;
+use crate::keycode::KEYBOARD_KEYS;
 
 pub const INPUT_DEVICE_PATH: &str = "/dev/input";
 
+/// How to find a device, so that we don't rely on the unstable `/dev/input/eventN`.
+///
+/// The string form is `<kind>:<value>`, e.g. `name:HHKB`, `id:04fe:0021`.
+/// A string starting with `/` is a path, and a string without a kind is a name.
+#[derive(Debug, Clone)]
+pub enum DeviceMatcher {
+    /// Device node path
+    Path(String),
+    /// Substring of the device name
+    Name(String),
+    /// Regex of the device name
+    NameRegex(Regex),
+    /// USB vendor and product id
+    Id { vendor: u16, product: u16 },
+    /// Physical path, e.g. `usb-0000:00:14.0-2/input0`
+    Phys(String),
+    /// Unique identifier, usually the serial number
+    Uniq(String),
+}
+
+impl DeviceMatcher {
+    pub fn is_match(&self, path: &str, device: &Device) -> bool {
+        match self {
+            DeviceMatcher::Path(p) => p == path,
+            DeviceMatcher::Name(name) => device.name().is_some_and(|x| x.contains(name.as_str())),
+            DeviceMatcher::NameRegex(re) => device.name().is_some_and(|x| re.is_match(x)),
+            DeviceMatcher::Id { vendor, product } => {
+                let id = device.input_id();
+                id.vendor() == *vendor && id.product() == *product
+            }
+            DeviceMatcher::Phys(phys) => device.physical_path() == Some(phys.as_str()),
+            DeviceMatcher::Uniq(uniq) => device.unique_name() == Some(uniq.as_str()),
+        }
+    }
+}
+
+impl fmt::Display for DeviceMatcher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeviceMatcher::Path(p) => write!(f, "{}", p),
+            DeviceMatcher::Name(name) => write!(f, "name:{}", name),
+            DeviceMatcher::NameRegex(re) => write!(f, "regex:{}", re),
+            DeviceMatcher::Id { vendor, product } => write!(f, "id:{:04x}:{:04x}", vendor, product),
+            DeviceMatcher::Phys(phys) => write!(f, "phys:{}", phys),
+            DeviceMatcher::Uniq(uniq) => write!(f, "uniq:{}", uniq),
+        }
+    }
+}
+
+impl DeviceMatcher {
+    /// A matcher of the given kind, None if the kind is unknown.
+    fn from_kind(kind: &str, value: &str) -> Option<Result<Self, String>> {
+        let kind = kind.trim().to_lowercase();
+        let value = value.trim();
+        let known = ["path", "name", "regex", "id", "phys", "uniq"];
+        if !known.contains(&kind.as_str()) {
+            return None;
+        }
+        if value.is_empty() {
+            return Some(Err(format!("empty device matcher: '{}:'", kind)));
+        }
+
+        let matcher = match &kind[..] {
+            "path" => Ok(DeviceMatcher::Path(value.to_string())),
+            "name" => Ok(DeviceMatcher::Name(value.to_string())),
+            "regex" => Regex::new(value)
+                .map(DeviceMatcher::NameRegex)
+                .map_err(|e| format!("invalid regex '{}': {}", value, e)),
+            "id" => {
+                let parse_hex = |s: &str| u16::from_str_radix(s.trim(), 16).ok();
+                value
+                    .split_once(':')
+                    .and_then(|(v, p)| Some((parse_hex(v)?, parse_hex(p)?)))
+                    .map(|(vendor, product)| DeviceMatcher::Id { vendor, product })
+                    .ok_or_else(|| format!("invalid id '{}', expect vendor:product in hex", value))
+            }
+            "phys" => Ok(DeviceMatcher::Phys(value.to_string())),
+            _ => Ok(DeviceMatcher::Uniq(value.to_string())),
+        };
+        Some(matcher)
+    }
+}
+
+/// A bare string without a known `<kind>:` prefix is a name, e.g. `Foo Inc.: Keyboard`.
+impl FromStr for DeviceMatcher {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        if input.starts_with('/') {
+            return Ok(DeviceMatcher::Path(input.to_string()));
+        }
+        if let Some((kind, value)) = input.split_once(':')
+            && let Some(matcher) = Self::from_kind(kind, value)
+        {
+            return matcher;
+        }
+        Ok(DeviceMatcher::Name(input.to_string()))
+    }
+}
+
+/// Accepts both `name:HHKB` and `{name: HHKB}` in the config file.
+impl<'de> Deserialize<'de> for DeviceMatcher {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct DeviceMatcherVisitor;
+
+        impl<'de> Visitor<'de> for DeviceMatcherVisitor {
+            type Value = DeviceMatcher;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("string or map with a single matcher")
+            }
+
+            fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
+            where
+                E: de::Error,
+            {
+                value.parse().map_err(E::custom)
+            }
+
+            fn visit_map<M>(self, mut map: M) -> Result<Self::Value, M::Error>
+            where
+                M: MapAccess<'de>,
+            {
+                let Some((kind, value)) = map.next_entry::<String, String>()? else {
+                    return Err(de::Error::custom("empty device matcher"));
+                };
+                if map.next_key::<String>()?.is_some() {
+                    return Err(de::Error::custom("only one matcher per device is allowed"));
+                }
+                DeviceMatcher::from_kind(&kind, &value)
+                    .unwrap_or_else(|| {
+                        Err(format!(
+                            "unknown device matcher '{}', expect one of path, name, regex, id, phys, uniq",
+                            kind
+                        ))
+                    })
+                    .map_err(de::Error::custom)
+            }
+        }
+
+        deserializer.deserialize_any(DeviceMatcherVisitor)
+    }
+}
+
+/// Classification of a device by its capabilities.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum DeviceKind {
+    /// Has all letter keys and Enter, and no relative axes.
+    Keyboard,
+    /// Has relative axes, e.g. mouse, or a keyboard with a trackpoint.
+    Pointer,
+    /// Our own output device.
+    Virtual,
+    /// Everything else, e.g. power button, lid switch, audio jack.
+    Other,
+}
+
+impl fmt::Display for DeviceKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            DeviceKind::Keyboard => "keyboard",
+            DeviceKind::Pointer => "pointer",
+            DeviceKind::Virtual => "virtual",
+            DeviceKind::Other => "other",
+        };
+        // Use `pad` so that width works.
+        f.pad(name)
+    }
+}
+
+/// Which devices to grab, also decides whether a hotplugged device is grabbed.
+#[derive(Debug, Clone)]
+pub enum DeviceSelector {
+    /// All real keyboards
+    Keyboards,
+    /// Devices matched by any of the matchers
+    Matchers(Vec<DeviceMatcher>),
+}
+
+impl DeviceSelector {
+    pub fn is_match(&self, path: &str, device: &Device) -> bool {
+        match self {
+            DeviceSelector::Keyboards => DeviceManager::classify(device) == DeviceKind::Keyboard,
+            DeviceSelector::Matchers(matchers) => matchers.iter().any(|x| x.is_match(path, device)),
+        }
+    }
+
+    /// Whether to grab a hotplugged device. Like at startup, a matcher stands for exactly one
+    /// device: it takes the new one only if none of `grabbed` matches it, and the new one is
+    /// the only match in `/dev/input`.
+    pub fn accepts_hotplug(
+        &self,
+        path: &str,
+        device: &Device,
+        grabbed: &[(String, Device)],
+    ) -> bool {
+        let DeviceSelector::Matchers(matchers) = self else {
+            return self.is_match(path, device);
+        };
+        let mut scanned = None;
+        for matcher in matchers.iter().filter(|x| x.is_match(path, device)) {
+            if grabbed.iter().any(|(p, d)| matcher.is_match(p, d)) {
+                debug!("{} has its device already, skip {}", matcher, path);
+                continue;
+            }
+            let all = scanned.get_or_insert_with(|| DeviceManager::scan().unwrap_or_default());
+            let mut matched: Vec<&String> = all
+                .iter()
+                .filter(|(p, d)| matcher.is_match(p, d))
+                .map(|(p, _)| p)
+                .collect();
+            if matched.len() > 1 {
+                matched.sort_by(|a, b| compare_path(a, b));
+                warn!(
+                    "{} matches more than one device, skip {}: {:?}",
+                    matcher, path, matched
+                );
+                continue;
+            }
+            return true;
+        }
+        false
+    }
+}
+
 pub struct DeviceManager {}
 
 impl DeviceManager {
     pub fn scan() -> Result<HashMap<String, Device>, Box<dyn Error>> {
         let mut path_devices = HashMap::new();
 
-        if let Some(dev_input) = read_dir(INPUT_DEVICE_PATH).as_mut().ok() {
-            while let Some(entry) = dev_input.next() {
+        if let Ok(dev_input) = read_dir(INPUT_DEVICE_PATH) {
+            for entry in dev_input {
                 let path = entry?.path();
-                if let Some(fname) = path.file_name() {
-                    if fname.as_bytes().starts_with(b"event") {
-                        let device = Device::open(&path)?;
-                        if let Ok(path) = path.into_os_string().into_string() {
-                            path_devices.insert(path, device);
+                if let Some(fname) = path.file_name()
+                    && fname.as_bytes().starts_with(b"event")
+                {
+                    // Skip what we can't open (e.g. permission), rather than fail the whole scan.
+                    let device = match Device::open(&path) {
+                        Ok(device) => device,
+                        Err(e) => {
+                            warn!("Failed to open {}: {}", path.display(), e);
+                            continue;
                         }
+                    };
+                    if let Ok(path) = path.into_os_string().into_string() {
+                        path_devices.insert(path, device);
                     }
                 }
             }
         }
-        return Ok(path_devices);
+        Ok(path_devices)
     }
 
     pub fn get_device(path: &str) -> Result<Device, Box<dyn Error>> {
         let device = Device::open(path)?;
         Ok(device)
     }
+
+    pub fn classify(device: &Device) -> DeviceKind {
+        if device.name() == Some(NAME) {
+            return DeviceKind::Virtual;
+        }
+        if device
+            .supported_relative_axes()
+            .is_some_and(|axes| axes.iter().next().is_some())
+        {
+            return DeviceKind::Pointer;
+        }
+        if device
+            .supported_keys()
+            .is_some_and(|keys| KEYBOARD_KEYS.iter().all(|key| keys.contains(*key)))
+        {
+            return DeviceKind::Keyboard;
+        }
+        DeviceKind::Other
+    }
+
+    /// Open the devices of the selector, returns `(path, device)` pairs.
+    pub fn select(selector: &DeviceSelector) -> Result<Vec<(String, Device)>, Box<dyn Error>> {
+        match selector {
+            DeviceSelector::Keyboards => Self::find_keyboards(),
+            DeviceSelector::Matchers(matchers) => Self::find_devices(matchers),
+        }
+    }
+
+    /// All real keyboards of this computer, used when no device is given.
+    pub fn find_keyboards() -> Result<Vec<(String, Device)>, Box<dyn Error>> {
+        let mut keyboards: Vec<(String, Device)> = Self::scan()?
+            .into_iter()
+            .filter(|(_, device)| Self::classify(device) == DeviceKind::Keyboard)
+            .collect();
+        if keyboards.is_empty() {
+            return Err(format!(
+                "No keyboard found in {}, please specify `--device`",
+                INPUT_DEVICE_PATH
+            )
+            .into());
+        }
+        keyboards.sort_by(|a, b| compare_path(&a.0, &b.0));
+        for (path, device) in keyboards.iter() {
+            info!("Found keyboard {}", Self::describe(path, device));
+        }
+        Ok(keyboards)
+    }
+
+    /// Resolve each matcher to exactly one device.
+    pub fn find_devices(
+        matchers: &[DeviceMatcher],
+    ) -> Result<Vec<(String, Device)>, Box<dyn Error>> {
+        let mut devices: Vec<(String, Device)> = vec![];
+        let mut scanned = None;
+
+        for matcher in matchers.iter() {
+            // Open path directly, no need to scan
+            if let DeviceMatcher::Path(path) = matcher {
+                if devices.iter().any(|(p, _)| p == path) {
+                    continue;
+                }
+                let device = Self::get_device(path)
+                    .map_err(|e| format!("Failed to open {}: {}", path, e))?;
+                devices.push((path.to_string(), device));
+                continue;
+            }
+
+            if scanned.is_none() {
+                scanned = Some(Self::scan()?);
+            }
+            let all = scanned.as_mut().unwrap();
+            let mut matched: Vec<&String> = all
+                .iter()
+                .filter(|(path, device)| matcher.is_match(path, device))
+                .map(|(path, _)| path)
+                .collect();
+            matched.sort_by(|a, b| compare_path(a, b));
+
+            let path = match matched[..] {
+                [path] => path.to_string(),
+                [] => {
+                    return Err(format!(
+                        "No device matches `{}`, available devices:\n{}",
+                        matcher,
+                        Self::describe_all(all.iter())
+                    )
+                    .into());
+                }
+                _ => {
+                    return Err(format!(
+                        "More than one device matches `{}`, please be more specific:\n{}",
+                        matcher,
+                        Self::describe_all(all.iter().filter(|(p, _)| matched.contains(p)))
+                    )
+                    .into());
+                }
+            };
+            if devices.iter().any(|(p, _)| p == &path) {
+                continue;
+            }
+            let device = all.remove(&path).unwrap();
+            devices.push((path, device));
+        }
+        Ok(devices)
+    }
+
+    /// One line summary of a device, with everything a matcher can use.
+    pub fn describe(path: &str, device: &Device) -> String {
+        let id = device.input_id();
+        format!(
+            "{:20}: {:8} [{:04x}:{:04x}] {}  phys={} uniq={}",
+            path,
+            Self::classify(device),
+            id.vendor(),
+            id.product(),
+            device.name().unwrap_or("Unknown Name"),
+            device.physical_path().unwrap_or("-"),
+            device
+                .unique_name()
+                .filter(|x| !x.is_empty())
+                .unwrap_or("-"),
+        )
+    }
+
+    fn describe_all<'b>(devices: impl Iterator<Item = (&'b String, &'b Device)>) -> String {
+        let mut devices: Vec<(&String, &Device)> = devices.collect();
+        devices.sort_by(|a, b| compare_path(a.0, b.0));
+        devices
+            .into_iter()
+            .map(|(path, device)| format!("  {}", Self::describe(path, device)))
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+}
+
+/// Sort `/dev/input/event2` before `/dev/input/event10`.
+pub fn compare_path(path_1: &str, path_2: &str) -> std::cmp::Ordering {
+    path_1
+        .len()
+        .cmp(&path_2.len())
+        .then_with(|| path_1.cmp(path_2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(input: &str) -> DeviceMatcher {
+        input.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_matchers() {
+        assert!(
+            matches!(parse("/dev/input/event2"), DeviceMatcher::Path(p) if p == "/dev/input/event2")
+        );
+        assert!(matches!(parse("name: HHKB"), DeviceMatcher::Name(n) if n == "HHKB"));
+        assert!(matches!(parse("HHKB"), DeviceMatcher::Name(n) if n == "HHKB"));
+        assert!(matches!(
+            parse("id:04fe:0021"),
+            DeviceMatcher::Id {
+                vendor: 0x04fe,
+                product: 0x0021
+            }
+        ));
+        assert!("id:04fe".parse::<DeviceMatcher>().is_err());
+        assert!("name:".parse::<DeviceMatcher>().is_err());
+    }
+
+    #[test]
+    fn bare_name_with_colon() {
+        let name = "Foo Inc.: Keyboard";
+        assert!(matches!(parse(name), DeviceMatcher::Name(n) if n == name));
+    }
+
+    #[test]
+    fn map_rejects_unknown_kind() {
+        assert!(serde_yaml::from_str::<DeviceMatcher>("{name: HHKB}").is_ok());
+        assert!(serde_yaml::from_str::<DeviceMatcher>("{nmae: HHKB}").is_err());
+    }
 }
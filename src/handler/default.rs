@@ -1,37 +1,49 @@
-use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::convert::From;
 use std::error::Error;
+use std::os::fd::RawFd;
+use std::time::{Duration, Instant};
 
 use evdev::EventType;
 use evdev::InputEvent;
 use evdev::KeyCode as Key;
 use evdev::uinput::VirtualDevice;
 use lazy_static::lazy_static;
-use log::debug;
+use log::{debug, info, warn};
 
-use super::EventHandler;
+use super::keymap::{
+    Chord, Condition, KeyMatchStruct, Keymap, MultiTap, PendingChord, PendingSequence, TapCounter,
+    format_sequence,
+};
+use super::one_shot::OneShotModifiers;
+use super::tap_hold::{Decision, PendingTapHold};
+use super::{EventHandler, PRESS, RELEASE, REPEAT};
 use crate::NAME;
 use crate::config::Action;
+use crate::config::AsciiInput;
 use crate::config::Config;
+use crate::config::Group;
 use crate::config::KeyCombo;
 use crate::config::Modifier;
+use crate::config::Side;
+use crate::config::Sides;
+use crate::config::TapHold;
+use crate::config::TriggerOn;
+use crate::config::UnicodeInput;
+use crate::config::WindowMatcher;
+use crate::control::Request;
 use crate::executor::execute;
 use crate::keycode::*;
 use crate::notification::send_notify;
 use crate::output::build_device;
-use crate::x11::X11Client;
-
-// The value of InputEvent
-const RELEASE: i32 = 0;
-const PRESS: i32 = 1;
-const REPEAT: i32 = 2;
+use crate::window::{self, WindowProvider};
 
 lazy_static! {
     pub static ref DEFAULT_MODE: String = "".to_string();
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
 enum KeyState {
     PRESSED = PRESS as isize,
     RELEASED = RELEASE as isize,
@@ -119,106 +131,254 @@ impl Default for Win {
     }
 }
 
-/// The inner struct for match keybinding.
-#[derive(Debug)]
-struct KeyMatchStruct {
-    in_: Vec<String>,
-    not_in: Vec<String>,
-    action: Action,
+/// Key bindings of each mode, keyed by mode name.
+type LookupTable = HashMap<String, Keymap>;
+
+/// Result of looking up a key combo.
+enum Lookup {
+    Action(Action, TriggerOn),
+    /// A prefix of key sequences, wait for the next key combo. Holds the matched combo.
+    Prefix(KeyCombo),
+    None,
+}
+
+/// The side a modifier is held on, `Either` for both.
+fn side_of(left: KeyState, right: KeyState) -> Side {
+    match (bool::from(left), bool::from(right)) {
+        (true, false) => Side::Left,
+        (false, true) => Side::Right,
+        _ => Side::Either,
+    }
 }
 
-pub struct DefaultEventHandler<'a> {
+pub struct DefaultEventHandler {
     // State
     shift: Shift,
     control: Control,
     alt: Alt,
     windows: Win,
+    /// Single key translation applied before anything else.
+    modmap: HashMap<Key, Key>,
+    /// Physical key => the key it was translated to when pressed, so that the
+    /// repeat and release events always match the press.
+    pressed_keys: HashMap<Key, Key>,
+    /// Dual-role keys, checked on the physical key before the modmap.
+    tap_hold: HashMap<Key, TapHold>,
+    /// The tap-hold key waiting for the decision, all key events are delayed meanwhile.
+    pending_tap_hold: Option<PendingTapHold>,
+    one_shot: OneShotModifiers,
+    /// Key => the user-declared modifier it belongs to.
+    custom_modifiers: HashMap<Key, String>,
+    /// Custom modifier keys held right now, they are never sent.
+    custom_pressed: HashMap<Key, String>,
     output_device: VirtualDevice,
+    /// Pass all keys through untouched, except the ones pressed before pausing.
+    paused: bool,
+    /// Where the config is loaded from, for reloading.
+    config_path: String,
     current_mode: Option<String>,
-    all_modes: Vec<&'a String>,
-    switch_mode_keys: HashMap<&'a KeyCombo, &'a String>,
-    cycle_switch_mode_key: Option<&'a KeyCombo>,
-    lookup_table: HashMap<&'a String, HashMap<&'a KeyCombo, KeyMatchStruct>>,
-    x11_client: RefCell<X11Client>,
+    /// Hold key => the mode active while it is held.
+    layer_keys: HashMap<Key, String>,
+    /// Layers held right now, the last one wins over `current_mode`.
+    layers: Vec<(Key, String)>,
+    /// Key => the action its press matched, its repeats and release follow the press
+    /// even if the layer or modifiers change meanwhile.
+    held_actions: HashMap<Key, (Action, TriggerOn)>,
+    /// Keys whose press switched modes or went into a key sequence, their repeats and
+    /// release are dropped.
+    swallowed_keys: HashSet<Key>,
+    all_modes: Vec<String>,
+    /// Modes entered while a matching window is focused, the first match wins
+    auto_modes: Vec<(String, Vec<WindowMatcher>)>,
+    /// Set while in a mode entered by `auto_when`: the mode to return to when the focus leaves
+    auto_return: Option<Option<String>>,
+    /// The auto mode the focused window matched last time, only a change of it switches modes
+    auto_target: Option<String>,
+    switch_mode_keys: HashMap<KeyCombo, String>,
+    cycle_switch_mode_key: Option<KeyCombo>,
+    lookup_table: LookupTable,
+    /// The prefix of a key sequence typed so far
+    pending_sequence: Option<PendingSequence>,
+    sequence_timeout: Duration,
+    /// The chord being pressed, its keys are held back.
+    pending_chord: Option<PendingChord>,
+    /// Keys of fired chords still held, their repeats and releases are swallowed.
+    chord_keys: HashSet<Key>,
+    chord_timeout: Duration,
+    leader_key: Option<KeyCombo>,
+    leader_timeout: Duration,
+    /// Notify the keys that may follow the leader key
+    leader_hint: bool,
+    tap_counter: TapCounter,
+    /// Which tap in a row the latest key press is
+    tap_count: usize,
+    tap_interval: Duration,
+    unicode_input: UnicodeInput,
+    ascii_input: AsciiInput,
+    /// Connected once some group has `in`/`notin`
+    window_provider: Option<Box<dyn WindowProvider>>,
+    /// False if the window context is disabled from the command line
+    window_context: bool,
 }
 
-impl<'a> DefaultEventHandler<'a> {
-    pub fn new(config: &'a Config) -> Result<Self, Box<dyn std::error::Error>> {
+impl DefaultEventHandler {
+    pub fn new(
+        config_path: &str,
+        config: &Config,
+        window_context: bool,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
         let output_device =
             build_device().map_err(|e| format!("Failed to build an output device: {}", e))?;
 
-        // Try to get the default mode
-        let current_mode = config
-            .options
-            .as_ref()
-            .and_then(|x| x.default_mode.as_ref());
+        let mut handler = Self {
+            shift: Shift::default(),
+            control: Control::default(),
+            alt: Alt::default(),
+            windows: Win::default(),
+            modmap: HashMap::new(),
+            pressed_keys: HashMap::new(),
+            tap_hold: HashMap::new(),
+            pending_tap_hold: None,
+            one_shot: OneShotModifiers::default(),
+            custom_modifiers: HashMap::new(),
+            custom_pressed: HashMap::new(),
+            output_device,
+            paused: false,
+            config_path: config_path.to_string(),
+            current_mode: None,
+            layer_keys: HashMap::new(),
+            layers: vec![],
+            held_actions: HashMap::new(),
+            swallowed_keys: HashSet::new(),
+            switch_mode_keys: HashMap::new(),
+            lookup_table: HashMap::new(),
+            pending_sequence: None,
+            sequence_timeout: Duration::ZERO,
+            pending_chord: None,
+            chord_keys: HashSet::new(),
+            chord_timeout: Duration::ZERO,
+            leader_key: None,
+            leader_timeout: Duration::ZERO,
+            leader_hint: false,
+            tap_counter: TapCounter::default(),
+            tap_count: 1,
+            tap_interval: Duration::ZERO,
+            unicode_input: UnicodeInput::default(),
+            ascii_input: AsciiInput::default(),
+            cycle_switch_mode_key: None,
+            all_modes: vec![],
+            auto_modes: vec![],
+            auto_return: None,
+            auto_target: None,
+            window_provider: None,
+            window_context,
+        };
+        handler.apply_config(config);
+        handler.update_auto_mode();
 
+        handler.reset()?;
+        Ok(handler)
+    }
+
+    /// Replace everything built from the config, the key states are untouched.
+    fn apply_config(&mut self, config: &Config) {
+        self.connect_window_provider(config);
         // Construct lookup table, O(1) HashMap is more faster for key matching.
         let (switch_mode_keys, lookup_table) = Self::construct_lookup_table(config);
-        let cycle_switch_mode_key = config
+        self.switch_mode_keys = switch_mode_keys;
+        self.lookup_table = lookup_table;
+        // The pending prefix may not exist anymore.
+        self.pending_sequence = None;
+        self.sequence_timeout = Duration::from_millis(
+            config
+                .options
+                .as_ref()
+                .map(|x| x.sequence_timeout)
+                .unwrap_or(1000),
+        );
+        self.chord_timeout = Duration::from_millis(
+            config
+                .options
+                .as_ref()
+                .map(|x| x.chord_timeout)
+                .unwrap_or(50),
+        );
+        self.leader_key = config.leader_key().cloned();
+        if let Some(options) = &config.options {
+            self.leader_timeout = Duration::from_millis(options.leader_timeout);
+            self.leader_hint = options.leader_hint;
+        }
+        self.tap_interval = Duration::from_millis(
+            config
+                .options
+                .as_ref()
+                .map(|x| x.tap_interval)
+                .unwrap_or(300),
+        );
+        self.unicode_input = config
             .options
             .as_ref()
-            .and_then(|x| x.mode_switch_key.as_ref());
-
-        let mut all_modes = vec![];
-        if let Some(modes) = &config.modes {
-            for m in modes.keys().into_iter() {
-                all_modes.push(m);
+            .map(|x| x.unicode_input)
+            .unwrap_or_default();
+        self.ascii_input = config
+            .options
+            .as_ref()
+            .map(|x| x.ascii_input)
+            .unwrap_or_default();
+        self.check_keyboard_layout(config);
+        self.cycle_switch_mode_key = config
+            .options
+            .as_ref()
+            .and_then(|x| x.mode_switch_key.clone());
+        self.modmap = config.modmap.clone().unwrap_or_default();
+        self.tap_hold = config.tap_hold.clone().unwrap_or_default();
+        self.one_shot = OneShotModifiers::new(config.one_shot.as_ref());
+        self.custom_modifiers = HashMap::new();
+        for (name, keys) in config.modifiers.iter().flatten() {
+            for key in keys.iter() {
+                self.custom_modifiers.insert(*key, name.to_string());
             }
         }
 
-        let x11_client;
-        let mut retry_count = 3;
-        loop {
-            match X11Client::new() {
-                Ok(res) => {
-                    x11_client = res;
-                    break;
+        self.all_modes = vec![];
+        self.auto_modes = vec![];
+        self.layer_keys = HashMap::new();
+        if let Some(modes) = &config.modes {
+            for (name, m) in modes.iter() {
+                self.all_modes.push(name.to_string());
+                if let Some(key) = m.hold_key {
+                    self.layer_keys.insert(key, name.to_string());
                 }
-                Err(e) => {
-                    if retry_count == 0 {
-                        return Err(e);
-                    }
+                if let Some(matchers) = &m.auto_when {
+                    self.auto_modes.push((name.to_string(), matchers.clone()));
                 }
             }
-            retry_count -= 1;
-            std::thread::sleep(std::time::Duration::from_millis(100));
         }
 
-        let mut handler = Self {
-            shift: Shift::default(),
-            control: Control::default(),
-            alt: Alt::default(),
-            windows: Win::default(),
-            output_device,
-            current_mode: current_mode.map(|x| x.to_string()),
-            switch_mode_keys,
-            lookup_table,
-            cycle_switch_mode_key,
-            all_modes,
-            x11_client: RefCell::new(x11_client),
+        // Keep the current mode if it still exists, otherwise try to get the default mode
+        let default_mode = config.options.as_ref().and_then(|x| x.default_mode.clone());
+        self.current_mode = match self.current_mode.take() {
+            Some(mode) if self.all_modes.contains(&mode) => Some(mode),
+            _ => default_mode.clone(),
         };
-
-        handler.reset()?;
-        Ok(handler)
+        if let Some(Some(mode)) = &self.auto_return
+            && !self.all_modes.contains(mode)
+        {
+            self.auto_return = Some(default_mode);
+        }
     }
 
-    fn construct_lookup_table(
-        raw_config: &'a Config,
-    ) -> (
-        HashMap<&'a KeyCombo, &'a String>,
-        HashMap<&'a String, HashMap<&'a KeyCombo, KeyMatchStruct>>,
-    ) {
+    fn construct_lookup_table(raw_config: &Config) -> (HashMap<KeyCombo, String>, LookupTable) {
         let mut res = HashMap::new();
 
-        let mut switch_mode_keys: HashMap<&KeyCombo, &String> = HashMap::new();
+        let mut switch_mode_keys: HashMap<KeyCombo, String> = HashMap::new();
 
         // check if we have modes
         if let Some(modes) = &raw_config.modes {
             for (name, mode) in modes.iter() {
                 // Construct switch mode key
                 if let Some(combo) = &mode.switch_key {
-                    switch_mode_keys.insert(combo, name);
+                    switch_mode_keys.insert(combo.clone(), name.to_string());
                 }
 
                 // Construct
@@ -229,42 +389,457 @@ impl<'a> DefaultEventHandler<'a> {
                     }
                 }
 
-                let mut kbs = HashMap::new();
+                let mut kbs = Keymap::default();
                 for g in groups.iter() {
-                    for kb in g.key_bindings.iter() {
-                        kbs.insert(
-                            &kb.key_combo,
-                            KeyMatchStruct {
-                                in_: g.in_.clone().unwrap_or_default(),
-                                not_in: g.not_in.clone().unwrap_or_default(),
-                                action: kb.get_action(),
-                            },
-                        );
-                    }
+                    Self::insert_group(&mut kbs, g, raw_config.leader_key());
                 }
 
-                res.insert(name, kbs);
+                res.insert(name.to_string(), kbs);
             }
         } else {
-            let mut kbs = HashMap::new();
+            let mut kbs = Keymap::default();
             for g in raw_config.groups.values() {
-                for kb in g.key_bindings.iter() {
-                    kbs.insert(
-                        &kb.key_combo,
-                        KeyMatchStruct {
-                            in_: g.in_.clone().unwrap_or_default(),
-                            not_in: g.not_in.clone().unwrap_or_default(),
-                            action: kb.get_action(),
-                        },
-                    );
-                }
+                Self::insert_group(&mut kbs, g, raw_config.leader_key());
             }
 
-            res.insert(&DEFAULT_MODE, kbs);
+            res.insert(DEFAULT_MODE.to_string(), kbs);
         }
         (switch_mode_keys, res)
     }
 
+    fn insert_group(kbs: &mut Keymap, group: &Group, leader_key: Option<&KeyCombo>) {
+        let condition = Condition {
+            in_: group.in_.clone().unwrap_or_default(),
+            not_in: group.not_in.clone().unwrap_or_default(),
+        };
+        for kb in group.key_bindings.iter() {
+            let binding = KeyMatchStruct {
+                condition: condition.clone(),
+                action: kb.get_action(),
+                on: kb.trigger_on(),
+                desc: kb.desc.clone(),
+            };
+            // A key sequence starting with the leader key
+            if let (Some(path), Some(leader_key)) = (&kb.leader, leader_key) {
+                let mut combos = vec![leader_key.clone()];
+                combos.extend(path.iter().cloned());
+                kbs.sequences.insert(&combos, binding);
+                continue;
+            }
+            match (&kb.chord, kb.taps) {
+                (Some(keys), _) => kbs.chords.push(Chord {
+                    keys: keys.clone(),
+                    binding,
+                }),
+                (None, Some(taps)) if taps > 1 => kbs.multi_taps.push(MultiTap {
+                    key_combo: kb.key_combos[0].clone(),
+                    taps,
+                    binding,
+                }),
+                _ => kbs.sequences.insert(&kb.key_combos, binding),
+            }
+        }
+    }
+
+    /// Translate the physical key through the modmap.
+    fn translate_key(&mut self, key: Key, value: i32) -> Key {
+        match value {
+            PRESS => {
+                let translated = match self.paused {
+                    true => key,
+                    false => self.modmap.get(&key).copied().unwrap_or(key),
+                };
+                self.pressed_keys.insert(key, translated);
+                translated
+            }
+            REPEAT => self.pressed_keys.get(&key).copied().unwrap_or(key),
+            _ => self.pressed_keys.remove(&key).unwrap_or(key),
+        }
+    }
+
+    /// Feed the event to the pending tap-hold key.
+    fn handle_pending_tap_hold(&mut self, event: InputEvent) -> Result<(), Box<dyn Error>> {
+        let pending = self.pending_tap_hold.as_mut().unwrap();
+        // Too late, it's a hold no matter what the event is.
+        if pending.is_expired(Instant::now()) {
+            self.resolve_tap_hold(Decision::Hold)?;
+            return self.handle_event(event);
+        }
+        if let Some(decision) = pending.feed(event) {
+            self.resolve_tap_hold(decision)?;
+        }
+        Ok(())
+    }
+
+    /// Emit the tap or hold key, then replay the delayed events.
+    fn resolve_tap_hold(&mut self, decision: Decision) -> Result<(), Box<dyn Error>> {
+        let Some(pending) = self.pending_tap_hold.take() else {
+            return Ok(());
+        };
+        debug!("Tap-hold {:?} is decided as {:?}", pending.key, decision);
+        // Not counted as taps, the physical key is the tap-hold key.
+        self.tap_count = 1;
+
+        match decision {
+            Decision::Tap => {
+                self.process_chord_key(pending.tap_hold.tap, PRESS)?;
+                self.process_chord_key(pending.tap_hold.tap, RELEASE)?;
+            }
+            Decision::Hold => {
+                // So that the release of the physical key is translated to the hold key.
+                self.pressed_keys.insert(pending.key, pending.tap_hold.hold);
+                self.process_chord_key(pending.tap_hold.hold, PRESS)?;
+            }
+        }
+
+        // The replayed events may start another tap-hold.
+        for event in pending.buffer {
+            self.handle_event(event)?;
+        }
+        Ok(())
+    }
+
+    /// Hold back the keys of a chord until it completes, fails or times out.
+    fn process_chord_key(&mut self, key: Key, value: i32) -> Result<(), Box<dyn Error>> {
+        // Keys of a fired chord are used up.
+        if self.chord_keys.contains(&key) {
+            if value == RELEASE {
+                self.chord_keys.remove(&key);
+            }
+            return Ok(());
+        }
+
+        if let Some(pending) = &self.pending_chord {
+            if value == REPEAT && pending.keys.contains(&key) {
+                return Ok(());
+            }
+            if value == PRESS && !pending.keys.contains(&key) {
+                let mut keys = pending.keys.clone();
+                keys.push(key);
+                if self.chords().iter().any(|x| x.contains_all(&keys)) {
+                    // Wait for more keys only if a longer chord may still come.
+                    let longer = self
+                        .chords()
+                        .iter()
+                        .any(|x| x.keys.len() > keys.len() && x.contains_all(&keys));
+                    if let Some(pending) = &mut self.pending_chord {
+                        pending.keys = keys;
+                    }
+                    if !longer {
+                        self.resolve_chord()?;
+                    }
+                    return Ok(());
+                }
+            }
+            // Not a chord after all, or a key of it is released early.
+            self.resolve_chord()?;
+            return self.process_chord_key(key, value);
+        }
+
+        if value == PRESS
+            && !self.paused
+            && !self.is_modifier_held()
+            && self.chords().iter().any(|x| x.keys.contains(&key))
+        {
+            self.pending_chord = Some(PendingChord {
+                keys: vec![key],
+                deadline: Instant::now() + self.chord_timeout,
+            });
+            return Ok(());
+        }
+
+        self.process_key(key, value)
+    }
+
+    /// Fire the pending chord if complete, otherwise send its keys as usual.
+    fn resolve_chord(&mut self) -> Result<(), Box<dyn Error>> {
+        let Some(pending) = self.pending_chord.take() else {
+            return Ok(());
+        };
+        let mut action = None;
+        for chord in self.chords().iter() {
+            if chord.is_complete(&pending.keys) && self.is_condition_match(&chord.binding.condition)
+            {
+                action = Some(chord.binding.action.clone());
+                break;
+            }
+        }
+        if let Some(action) = action {
+            debug!("Chord {:?} => {:?}", pending.keys, action);
+            self.chord_keys.extend(pending.keys);
+            return self.dispatch_action(&action);
+        }
+        for key in pending.keys {
+            self.process_key(key, PRESS)?;
+        }
+        Ok(())
+    }
+
+    fn chords(&self) -> &[Chord] {
+        self.lookup_table
+            .get(self.active_mode())
+            .map(|x| x.chords.as_slice())
+            .unwrap_or_default()
+    }
+
+    fn is_modifier_held(&self) -> bool {
+        [
+            self.shift.left,
+            self.shift.right,
+            self.control.left,
+            self.control.right,
+            self.alt.left,
+            self.alt.right,
+            self.windows.left,
+            self.windows.right,
+        ]
+        .into_iter()
+        .any(bool::from)
+            || !self.custom_pressed.is_empty()
+    }
+
+    /// Process the translated key, match key bindings and send the result.
+    fn process_key(&mut self, key: Key, value: i32) -> Result<(), Box<dyn Error>> {
+        if value == PRESS {
+            self.one_shot.press(key);
+        }
+
+        // Layer keys are swallowed, they even take over modifier keys.
+        if self.layers.iter().any(|(k, _)| *k == key) {
+            if value == RELEASE {
+                self.layers.retain(|(k, _)| *k != key);
+                self.pending_sequence = None;
+                debug!("Layer released => {:?}", self.layers);
+            }
+            return Ok(());
+        }
+        if value == PRESS
+            && !self.paused
+            && let Some(mode) = self.layer_keys.get(&key)
+        {
+            self.layers.push((key, mode.to_string()));
+            self.pending_sequence = None;
+            debug!("Layer pressed => {:?}", self.layers);
+            return Ok(());
+        }
+
+        // Custom modifier keys only take part in the matching.
+        if self.custom_pressed.contains_key(&key) {
+            if value == RELEASE {
+                self.custom_pressed.remove(&key);
+            }
+            return Ok(());
+        }
+        if value == PRESS
+            && !self.paused
+            && let Some(name) = self.custom_modifiers.get(&key)
+        {
+            self.custom_pressed.insert(key, name.to_string());
+            return Ok(());
+        }
+
+        // The last tap of a multi-tap replaces the key, even a modifier key.
+        if value == PRESS
+            && !self.paused
+            && self.tap_count > 1
+            && let Some(action) = self.find_multi_tap(&self.current_combo(key))?
+        {
+            debug!("{} taps of {:?} => {:?}", self.tap_count, key, action);
+            self.held_actions
+                .insert(key, (action.clone(), TriggerOn::Press));
+            self.dispatch_action(&action)?;
+            return Ok(());
+        }
+
+        // The press of this key fired an action already, even a modifier key's.
+        if value == RELEASE
+            && let Some((action, on)) = self.held_actions.remove(&key)
+        {
+            if on == TriggerOn::Release {
+                self.dispatch_action(&action)?;
+            }
+            return Ok(());
+        }
+        if value == REPEAT
+            && let Some((action, on)) = self.held_actions.get(&key).cloned()
+        {
+            if on == TriggerOn::Repeat {
+                self.dispatch_action(&action)?;
+            }
+            return Ok(());
+        }
+
+        // The mapping of modifier keys is handled first, as it affects the matching later.
+        if MODIFIER_KEYS.contains(&key) {
+            let state = if value == PRESS || value == REPEAT {
+                KeyState::PRESSED
+            } else {
+                KeyState::RELEASED
+            };
+            self.update_modifier_state(key, state);
+            if value == RELEASE && !self.paused {
+                self.one_shot.release(key, Instant::now());
+            }
+            self.send_key(&key, value)?;
+            return Ok(());
+        }
+
+        if value != PRESS && self.swallowed_keys.contains(&key) {
+            if value == RELEASE {
+                self.swallowed_keys.remove(&key);
+            }
+            return Ok(());
+        }
+        // Only a press looks up bindings, a repeat never fires anything on its own.
+        if value != PRESS || self.paused {
+            self.send_key(&key, value)?;
+            return Ok(());
+        }
+
+        // So what key combo we pressed?
+        let mut key_combo = self.current_combo(key);
+        // Sticky modifiers count as pressed, the repeats don't get them anymore.
+        let one_shot = self.one_shot.apply(&mut key_combo, Instant::now());
+        debug!("Current Key Combo => {:?}", key_combo);
+        self.process_combo(key, key_combo, one_shot)
+    }
+
+    /// Match the key combo of a key press, `one_shot` if it has sticky modifiers applied.
+    fn process_combo(
+        &mut self,
+        key: Key,
+        key_combo: KeyCombo,
+        one_shot: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        // Escape cancels the key sequence typed so far.
+        if key == Key::KEY_ESC
+            && let Some(pending) = self.pending_sequence.take()
+        {
+            debug!(
+                "Key sequence cancelled => {}",
+                format_sequence(&pending.combos)
+            );
+            self.swallowed_keys.insert(key);
+            return Ok(());
+        }
+
+        // Shall we switch to next mode?
+        if self.pending_sequence.is_none()
+            && let Some(next_mode) = key_combo
+                .candidates()
+                .iter()
+                .find_map(|x| self.check_mode_switching(x))
+        {
+            self.switch_mode(&next_mode);
+            self.swallowed_keys.insert(key);
+            return Ok(());
+        }
+
+        let mode = self.active_mode().to_string();
+
+        // Find action and execute
+        match self.find_action(&mode, &key_combo)? {
+            Lookup::Action(action, on) => {
+                debug!("Find key binding action => {:?}", action);
+                self.pending_sequence = None;
+                self.held_actions.insert(key, (action.clone(), on));
+                if on != TriggerOn::Release {
+                    self.dispatch_action(&action)?;
+                }
+                return Ok(());
+            }
+            Lookup::Prefix(matched) => {
+                let pending = self.pending_sequence.get_or_insert(PendingSequence {
+                    combos: Vec::new(),
+                    path: Vec::new(),
+                    deadline: Instant::now(),
+                });
+                pending.combos.push(key_combo);
+                pending.path.push(matched);
+                self.swallowed_keys.insert(key);
+                let is_leader = pending.path.first() == self.leader_key.as_ref();
+                pending.deadline = Instant::now()
+                    + if is_leader {
+                        self.leader_timeout
+                    } else {
+                        self.sequence_timeout
+                    };
+                debug!(
+                    "Pending key sequence => {}",
+                    format_sequence(&pending.combos)
+                );
+                if is_leader && self.leader_hint {
+                    self.notify_continuations(&mode);
+                }
+                return Ok(());
+            }
+            Lookup::None => {
+                // Not a sequence after all, give the swallowed keys back and
+                // look up this key on its own.
+                if let Some(pending) = self.pending_sequence.take() {
+                    self.replay_sequence(pending)?;
+                    // The sticky modifiers are used up already, keep them on this key.
+                    return self.process_combo(key, key_combo, one_shot);
+                }
+            }
+        }
+
+        // The sticky modifiers are held for this key only.
+        if one_shot {
+            self.send_combo(&key_combo)?;
+            return Ok(());
+        }
+
+        // Make sure the event is sent, otherwise it will get stuck
+        self.send_key(&key, PRESS)?;
+        Ok(())
+    }
+
+    /// Send the keys held back by a key sequence that didn't complete, except the leader key.
+    fn replay_sequence(&mut self, pending: PendingSequence) -> Result<(), Box<dyn Error>> {
+        let is_leader = pending.path.first() == self.leader_key.as_ref();
+        for combo in pending.combos.into_iter().skip(is_leader as usize) {
+            self.dispatch_action(&Action::Remap(combo))?;
+        }
+        Ok(())
+    }
+
+    /// The key combo of the key with the modifiers held right now.
+    fn current_combo(&self, key: Key) -> KeyCombo {
+        KeyCombo {
+            key,
+            shift: bool::from(self.shift.left) || bool::from(self.shift.right),
+            control: bool::from(self.control.left) || bool::from(self.control.right),
+            alt: bool::from(self.alt.left) || bool::from(self.alt.right),
+            windows: bool::from(self.windows.left) || bool::from(self.windows.right),
+            sides: Sides {
+                shift: side_of(self.shift.left, self.shift.right),
+                control: side_of(self.control.left, self.control.right),
+                alt: side_of(self.alt.left, self.alt.right),
+                windows: side_of(self.windows.left, self.windows.right),
+            },
+            custom: self.custom_pressed.values().cloned().collect(),
+        }
+    }
+
+    /// The multi-tap binding for the current tap count.
+    fn find_multi_tap(&self, key_combo: &KeyCombo) -> Result<Option<Action>, Box<dyn Error>> {
+        let Some(kbs) = self.lookup_table.get(self.active_mode()) else {
+            return Ok(None);
+        };
+        for candidate in key_combo.candidates() {
+            for multi_tap in kbs.multi_taps.iter() {
+                if multi_tap.taps == self.tap_count
+                    && multi_tap.key_combo == candidate
+                    && self.is_condition_match(&multi_tap.binding.condition)
+                {
+                    return Ok(Some(multi_tap.binding.action.clone()));
+                }
+            }
+        }
+        Ok(None)
+    }
+
     /// Update state of modifier keys.
     fn update_modifier_state(&mut self, key: Key, state: KeyState) {
         match key {
@@ -298,7 +873,17 @@ impl<'a> DefaultEventHandler<'a> {
         };
     }
 
-    fn get_expect_state(&self, modifier: Modifier, pressed: KeyState) -> (KeyState, KeyState) {
+    fn get_expect_state(
+        &self,
+        modifier: Modifier,
+        pressed: KeyState,
+        side: Side,
+    ) -> (KeyState, KeyState) {
+        match (pressed, side) {
+            (KeyState::PRESSED, Side::Left) => return (KeyState::PRESSED, KeyState::RELEASED),
+            (KeyState::PRESSED, Side::Right) => return (KeyState::RELEASED, KeyState::PRESSED),
+            _ => {}
+        }
         let press_state = match modifier {
             Modifier::Shift => (self.shift.left, self.shift.right),
             Modifier::Control => (self.control.left, self.control.right),
@@ -307,10 +892,10 @@ impl<'a> DefaultEventHandler<'a> {
         };
 
         if (bool::from(press_state.0) || bool::from(press_state.1)) == bool::from(pressed) {
-            press_state.clone() // no change is needed
+            press_state // no change is needed
         } else if pressed == KeyState::PRESSED {
-            // just press left
-            (KeyState::PRESSED, KeyState::PRESSED)
+            // just press left, the right Alt may be AltGr
+            (KeyState::PRESSED, KeyState::RELEASED)
         } else {
             // release all
             (KeyState::RELEASED, KeyState::RELEASED)
@@ -322,7 +907,7 @@ impl<'a> DefaultEventHandler<'a> {
         let mut next_mode = None;
         if let Some(current_mode) = &self.current_mode {
             if self.cycle_switch_mode_key.is_some()
-                && &key_combo == self.cycle_switch_mode_key.as_ref().unwrap()
+                && key_combo == self.cycle_switch_mode_key.as_ref().unwrap()
             {
                 let mut flag = false;
                 // Make a cycle
@@ -336,57 +921,246 @@ impl<'a> DefaultEventHandler<'a> {
                         next_mode = Some(mode.to_string());
                         break;
                     }
-                    if mode == &current_mode {
+                    if mode == current_mode {
                         flag = true;
                     }
                 }
             }
 
             if next_mode.is_none() {
-                next_mode = self.switch_mode_keys.get(&key_combo).map(|x| x.to_string());
+                next_mode = self.switch_mode_keys.get(key_combo).map(|x| x.to_string());
             }
         }
 
         next_mode
     }
 
+    fn switch_mode(&mut self, next_mode: &str) {
+        debug!(
+            "Mode is switching from {:?} to {:?}",
+            self.current_mode, next_mode
+        );
+        // Switching by hand stays, even after the focus leaves.
+        self.auto_return = None;
+        self.current_mode = Some(next_mode.to_string());
+        self.pending_sequence = None;
+        send_notify(
+            NAME,
+            &format!("{} is switching to {} mode.", NAME, next_mode),
+        )
+        .ok();
+    }
+
+    /// Enter the mode whose `auto_when` matches the focused window, or go back to the mode
+    /// before it once none matches.
+    fn update_auto_mode(&mut self) {
+        let Some(provider) = &self.window_provider else {
+            return;
+        };
+        let window = provider.focused();
+        let target = self
+            .auto_modes
+            .iter()
+            .find(|(_, matchers)| window.is_some_and(|w| matchers.iter().any(|m| m.is_match(w))))
+            .map(|(name, _)| name.clone());
+        // Title changes of the same window must not undo a switch by hand.
+        if target == self.auto_target {
+            return;
+        }
+        self.auto_target = target.clone();
+        match target {
+            Some(mode) => {
+                if self.current_mode.as_ref() == Some(&mode) {
+                    return;
+                }
+                let previous = match self.auto_return.take() {
+                    Some(previous) => previous,
+                    None => self.current_mode.clone(),
+                };
+                self.switch_mode(&mode);
+                self.auto_return = Some(previous);
+            }
+            None => {
+                let Some(previous) = self.auto_return.take() else {
+                    return;
+                };
+                if previous == self.current_mode {
+                    return;
+                }
+                match previous {
+                    Some(mode) => self.switch_mode(&mode),
+                    None => {
+                        debug!("Mode is switching from {:?} to None", self.current_mode);
+                        self.current_mode = None;
+                        self.pending_sequence = None;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Show the keys that may follow the pending key sequence.
+    fn notify_continuations(&self, mode: &str) {
+        let Some(pending) = &self.pending_sequence else {
+            return;
+        };
+        let Some(node) = self
+            .lookup_table
+            .get(mode)
+            .and_then(|x| x.sequences.get(&pending.path))
+        else {
+            return;
+        };
+        send_notify(
+            &format_sequence(&pending.path),
+            &node.describe_children().join("\n"),
+        )
+        .ok();
+    }
+
+    /// Key bindings of the current mode, one per line.
+    fn describe_bindings(&self) -> String {
+        let Some(kbs) = self.lookup_table.get(self.active_mode()) else {
+            return String::new();
+        };
+        let mut lines: Vec<String> = Vec::new();
+        for chord in kbs.chords.iter() {
+            let keys: Vec<String> = chord.keys.iter().map(|x| format!("{:?}", x)).collect();
+            let mut line = format!("{:<24} {}", keys.join("+"), chord.binding.action);
+            if let Some(desc) = &chord.binding.desc {
+                line.push_str(&format!("  # {}", desc));
+            }
+            lines.push(line);
+        }
+        for multi_tap in kbs.multi_taps.iter() {
+            let trigger = format!("{} x{}", multi_tap.key_combo, multi_tap.taps);
+            let mut line = format!("{:<24} {}", trigger, multi_tap.binding.action);
+            if let Some(desc) = &multi_tap.binding.desc {
+                line.push_str(&format!("  # {}", desc));
+            }
+            lines.push(line);
+        }
+        kbs.sequences.walk(&mut Vec::new(), &mut |combos, s| {
+            let mut line = format!("{:<24} {}", format_sequence(combos), s.action);
+            if let Some(desc) = &s.desc {
+                line.push_str(&format!("  # {}", desc));
+            }
+            lines.push(line);
+        });
+        lines.sort();
+        lines.iter().map(|x| format!("{}\n", x)).collect()
+    }
+
     fn dispatch_action(&mut self, action: &Action) -> Result<(), Box<dyn Error>> {
         debug!("Dispatch action => {:?}", action);
 
         match action {
             // Remap the key
-            Action::Remap(key_press) => {
-                let expect_shift = self.get_expect_state(Modifier::Shift, key_press.shift.into());
-                let expect_control =
-                    self.get_expect_state(Modifier::Control, key_press.control.into());
-                let expect_alt = self.get_expect_state(Modifier::Alt, key_press.alt.into());
-                let expect_windows =
-                    self.get_expect_state(Modifier::Windows, key_press.windows.into());
-
-                let prev_shift = self.send_modifier(Modifier::Shift, &expect_shift)?;
-                let prev_control = self.send_modifier(Modifier::Control, &expect_control)?;
-                let prev_alt = self.send_modifier(Modifier::Alt, &expect_alt)?;
-                let prev_windows = self.send_modifier(Modifier::Windows, &expect_windows)?;
-
-                self.send_key(&key_press.key, PRESS)?;
-                self.send_key(&key_press.key, RELEASE)?;
-
-                self.send_modifier(Modifier::Windows, &prev_windows)?;
-                self.send_modifier(Modifier::Alt, &prev_alt)?;
-                self.send_modifier(Modifier::Control, &prev_control)?;
-                self.send_modifier(Modifier::Shift, &prev_shift)?;
+            Action::Remap(key_press) => self.send_combo(key_press)?,
+            // Remap to several keys, one after another. The delay blocks all input.
+            Action::Macro(key_presses, delay) => {
+                for (i, key_press) in key_presses.iter().enumerate() {
+                    if i > 0 && *delay > 0 {
+                        std::thread::sleep(Duration::from_millis(*delay));
+                    }
+                    self.send_combo(key_press)?;
+                }
             }
             // Execute shell command
             Action::Shell(command) => {
                 //let mut res = shlex::split(command).unwrap();
                 //let args = res.split_off(1);
                 //execute(res[0].clone(), args);
-                execute(&command);
+                execute(command);
+            }
+            // Type the text
+            Action::Type(text) => self.type_text(text)?,
+        }
+        Ok(())
+    }
+
+    /// Tap the key combo, the modifiers are restored afterwards.
+    fn send_combo(&mut self, key_press: &KeyCombo) -> Result<(), Box<dyn Error>> {
+        let sides = key_press.sides;
+        let expect_shift =
+            self.get_expect_state(Modifier::Shift, key_press.shift.into(), sides.shift);
+        let expect_control =
+            self.get_expect_state(Modifier::Control, key_press.control.into(), sides.control);
+        let expect_alt = self.get_expect_state(Modifier::Alt, key_press.alt.into(), sides.alt);
+        let expect_windows =
+            self.get_expect_state(Modifier::Windows, key_press.windows.into(), sides.windows);
+
+        let prev_shift = self.send_modifier(Modifier::Shift, &expect_shift)?;
+        let prev_control = self.send_modifier(Modifier::Control, &expect_control)?;
+        let prev_alt = self.send_modifier(Modifier::Alt, &expect_alt)?;
+        let prev_windows = self.send_modifier(Modifier::Windows, &expect_windows)?;
+
+        self.send_key(&key_press.key, PRESS)?;
+        self.send_key(&key_press.key, RELEASE)?;
+
+        self.send_modifier(Modifier::Windows, &prev_windows)?;
+        self.send_modifier(Modifier::Alt, &prev_alt)?;
+        self.send_modifier(Modifier::Control, &prev_control)?;
+        self.send_modifier(Modifier::Shift, &prev_shift)?;
+        Ok(())
+    }
+
+    /// `type` presses the keys of a US layout for ASCII, other layouts get other characters.
+    fn check_keyboard_layout(&self, config: &Config) {
+        if self.ascii_input != AsciiInput::Us || !config.has_type_bindings() {
+            return;
+        }
+        match window::keyboard_layout(self.window_context) {
+            Some(layout) if layout.split(',').next() != Some("us") => warn!(
+                "`type` assumes a US keyboard layout but it is {}, set `ascii_input: unicode` in options",
+                layout
+            ),
+            Some(_) => {}
+            None => debug!("Unknown keyboard layout, `type` assumes US"),
+        }
+    }
+
+    /// Type the text, ASCII goes through a US layout unless `ascii_input` says otherwise.
+    fn type_text(&mut self, text: &str) -> Result<(), Box<dyn Error>> {
+        for c in text.chars() {
+            if self.ascii_input == AsciiInput::Us
+                && let Some((key, shift)) = ASCII_KEYS.get(&c)
+            {
+                self.send_combo(&Self::plain_combo(*key, *shift))?;
+                continue;
+            }
+            match self.unicode_input {
+                UnicodeInput::CtrlShiftU => {
+                    self.send_combo(&KeyCombo {
+                        control: true,
+                        ..Self::plain_combo(Key::KEY_U, true)
+                    })?;
+                    for digit in format!("{:x}", c as u32).chars() {
+                        let (key, _) = ASCII_KEYS[&digit];
+                        self.send_combo(&Self::plain_combo(key, false))?;
+                    }
+                    self.send_combo(&Self::plain_combo(Key::KEY_SPACE, false))?;
+                }
+                UnicodeInput::Ignore => {
+                    warn!("No key to type {:?}, skip it", c);
+                }
             }
         }
         Ok(())
     }
 
+    fn plain_combo(key: Key, shift: bool) -> KeyCombo {
+        KeyCombo {
+            key,
+            shift,
+            control: false,
+            alt: false,
+            windows: false,
+            sides: Sides::default(),
+            custom: BTreeSet::new(),
+        }
+    }
+
     fn send_modifier(
         &mut self,
         modifier: Modifier,
@@ -401,9 +1175,8 @@ impl<'a> DefaultEventHandler<'a> {
             Modifier::Control => (self.control.left, self.control.right),
             Modifier::Alt => (self.alt.left, self.alt.right),
             Modifier::Windows => (self.windows.left, self.windows.right),
-        }
-        .clone();
-        let original = current.clone();
+        };
+        let original = current;
         let left_key = match modifier {
             Modifier::Shift => Key::KEY_LEFTSHIFT,
             Modifier::Control => Key::KEY_LEFTCTRL,
@@ -462,47 +1235,77 @@ impl<'a> DefaultEventHandler<'a> {
         Ok(original)
     }
 
+    /// The innermost held layer, or the current mode.
+    fn active_mode(&self) -> &str {
+        match self.layers.last() {
+            Some((_, mode)) => mode,
+            None => self.current_mode.as_ref().unwrap_or(&DEFAULT_MODE),
+        }
+    }
+
+    /// Look up the key combo after the pending prefix, if any.
     fn find_action(
         &self,
+        mode: &str,
         key_combo: &KeyCombo,
-    ) -> Result<Option<Action>, Box<dyn std::error::Error>> {
-        // Find the action if we are using multi mode
-        let current_mode = self.current_mode.as_ref().unwrap_or(&DEFAULT_MODE);
-        let s = self
+    ) -> Result<Lookup, Box<dyn std::error::Error>> {
+        let prefix = self
+            .pending_sequence
+            .as_ref()
+            .map(|x| x.path.as_slice())
+            .unwrap_or_default();
+        let Some(parent) = self
             .lookup_table
-            .get(current_mode)
-            .and_then(|x| x.get(key_combo));
+            .get(mode)
+            .and_then(|x| x.sequences.get(prefix))
+        else {
+            return Ok(Lookup::None);
+        };
 
-        if s.is_none() {
-            return Ok(None);
+        // Side-specific bindings win over the ones for either side.
+        for candidate in key_combo.candidates() {
+            let Some(node) = parent.children.get(&candidate) else {
+                continue;
+            };
+            if node.is_prefix_for(|x| self.is_condition_match(x)) {
+                return Ok(Lookup::Prefix(candidate));
+            }
+            if let Some(s) = &node.binding
+                && self.is_condition_match(&s.condition)
+            {
+                return Ok(Lookup::Action(s.action.clone(), s.on));
+            }
         }
+        Ok(Lookup::None)
+    }
 
-        let s = s.unwrap();
+    /// Connect to the display server the first time a config needs the focused window.
+    fn connect_window_provider(&mut self, config: &Config) {
+        if self.window_provider.is_some() || !config.has_window_conditions() {
+            return;
+        }
+        if !self.window_context {
+            warn!("The window context is disabled, `in` and `auto_when` never match");
+            return;
+        }
+        match window::connect() {
+            Ok(provider) => self.window_provider = Some(provider),
+            Err(e) => warn!(
+                "Failed to get the focused window: {}, `in` and `auto_when` never match",
+                e
+            ),
+        }
+    }
 
+    fn is_condition_match(&self, condition: &Condition) -> bool {
         // Check application name only if we have `in` and `notin` field
-        if !s.in_.is_empty() || !s.not_in.is_empty() {
-            // Reconnect
-            let mut x11_client = self.x11_client.borrow_mut();
-            let wm_class;
-            match x11_client.get_focus_window_wmclass() {
-                Ok(res) => {
-                    wm_class = res;
-                }
-                Err(_) => {
-                    x11_client.reconnect()?;
-                    wm_class = x11_client.get_focus_window_wmclass()?;
-                }
-            }
-            let class_name = std::str::from_utf8(wm_class.class())?.to_string();
-            if !s.in_.is_empty() && !s.in_.contains(&class_name) {
-                return Ok(None);
-            }
-            if !s.not_in.is_empty() && s.not_in.contains(&class_name) {
-                return Ok(None);
-            }
+        if condition.is_empty() {
+            return true;
         }
-
-        return Ok(Some(s.action.clone()));
+        // Served from the cache. Without a window context the window is unknown,
+        // like a failed lookup: `in` never matches and `notin` always does.
+        let window = self.window_provider.as_ref().and_then(|x| x.focused());
+        condition.is_match(window)
     }
 
     fn send_key(&mut self, key: &Key, value: i32) -> std::io::Result<()> {
@@ -523,7 +1326,7 @@ impl<'a> DefaultEventHandler<'a> {
     }
 }
 
-impl<'a> EventHandler for DefaultEventHandler<'a> {
+impl EventHandler for DefaultEventHandler {
     /// Processes the event and execute corresponding action. e.g. Shell, Remap
     fn handle_event(&mut self, event: InputEvent) -> Result<(), Box<dyn Error>> {
         // Just send the event we don't care.
@@ -533,59 +1336,226 @@ impl<'a> EventHandler for DefaultEventHandler<'a> {
         }
         debug!("Receive KEY event => {:?}", event);
 
-        let key = Key::new(event.code());
+        // A pending tap-hold key takes everything until it's decided.
+        if self.pending_tap_hold.is_some() {
+            return self.handle_pending_tap_hold(event);
+        }
 
-        // The mapping of modifier keys is handled first, as it affects the matching later.
-        if MODIFIER_KEYS.contains(&key) {
-            let state = if event.value() == PRESS || event.value() == REPEAT {
-                KeyState::PRESSED
-            } else {
-                KeyState::RELEASED
-            };
-            self.update_modifier_state(key, state);
-            self.send_key(&key, event.value())?;
+        let key = Key::new(event.code());
+        if event.value() == PRESS
+            && !self.paused
+            && let Some(tap_hold) = self.tap_hold.get(&key)
+        {
+            self.pending_tap_hold =
+                Some(PendingTapHold::new(key, tap_hold.clone(), Instant::now()));
             return Ok(());
         }
 
-        if event.value() == (KeyState::RELEASED as i32) {
-            self.send_key(&key, event.value())?;
-            return Ok(());
+        // Modmap goes first, the translated key is what we see from now on.
+        let key = self.translate_key(key, event.value());
+        match event.value() {
+            PRESS => {
+                self.tap_count = self
+                    .tap_counter
+                    .press(key, event.timestamp(), self.tap_interval);
+            }
+            RELEASE => self.tap_counter.release(key, event.timestamp()),
+            _ => {}
         }
+        self.process_chord_key(key, event.value())
+    }
 
-        // So what key combo we pressed?
-        let key_combo = KeyCombo {
-            key: key.clone(),
-            shift: bool::from(self.shift.left) || bool::from(self.shift.right),
-            control: bool::from(self.control.left) || bool::from(self.control.right),
-            alt: bool::from(self.alt.left) || bool::from(self.alt.right),
-            windows: bool::from(self.windows.left) || bool::from(self.windows.right),
-        };
-        debug!("Current Key Combo => {:?}", key_combo);
+    fn window_fd(&self) -> Option<RawFd> {
+        self.window_provider.as_ref().and_then(|x| x.fd())
+    }
 
-        // Shall we switch to next mode?
-        if let Some(next_mode) = self.check_mode_switching(&key_combo) {
+    fn handle_window_event(&mut self) -> Result<(), Box<dyn Error>> {
+        if let Some(provider) = &mut self.window_provider {
+            provider.handle_events();
+        }
+        self.update_auto_mode();
+        Ok(())
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        let tap_hold = self.pending_tap_hold.as_ref().map(|x| x.deadline);
+        let sequence = self.pending_sequence.as_ref().map(|x| x.deadline);
+        let chord = self.pending_chord.as_ref().map(|x| x.deadline);
+        let window = self
+            .window_provider
+            .as_ref()
+            .and_then(|x| x.retry_deadline());
+        tap_hold
+            .into_iter()
+            .chain(sequence)
+            .chain(chord)
+            .chain(window)
+            .min()
+    }
+
+    fn handle_timeout(&mut self) -> Result<(), Box<dyn Error>> {
+        if let Some(pending) = &self.pending_tap_hold
+            && pending.is_expired(Instant::now())
+        {
+            self.resolve_tap_hold(Decision::Hold)?;
+        }
+        if let Some(pending) = &self.pending_chord
+            && Instant::now() >= pending.deadline
+        {
+            self.resolve_chord()?;
+        }
+        // The keys of an unfinished key sequence are sent after all, a leader sequence is cancelled.
+        if let Some(pending) = &self.pending_sequence
+            && Instant::now() >= pending.deadline
+        {
             debug!(
-                "Mode is switching from {:?} to {:?}",
-                self.current_mode, next_mode
+                "Key sequence timeout => {}",
+                format_sequence(&pending.combos)
             );
-            self.current_mode = Some(next_mode.to_string());
-            send_notify(
-                NAME,
-                &format!("{} is switching to {} mode.", NAME, next_mode),
-            )
-            .ok();
-            return Ok(());
+            let pending = self.pending_sequence.take().unwrap();
+            if pending.path.first() != self.leader_key.as_ref() {
+                self.replay_sequence(pending)?;
+            }
         }
-
-        // Find action and execute
-        if let Some(action) = self.find_action(&key_combo)? {
-            debug!("Find key binding action => {:?}", action);
-            self.dispatch_action(&action)?;
-            return Ok(());
+        // The display server may be back, e.g. after restarting it.
+        if let Some(provider) = &mut self.window_provider
+            && provider
+                .retry_deadline()
+                .is_some_and(|x| Instant::now() >= x)
+        {
+            provider.retry();
+            self.update_auto_mode();
         }
+        Ok(())
+    }
 
-        // Make sure the event is sent, otherwise it will get stuck
-        self.send_key(&key, event.value())?;
+    fn reload(&mut self) -> Result<(), Box<dyn Error>> {
+        info!("Reloading config from {}", self.config_path);
+        let config = match Config::load_from_file(&self.config_path) {
+            Ok(config) => config,
+            Err(e) => {
+                send_notify(
+                    NAME,
+                    &format!("Failed to reload config, keep the old one: {}", e),
+                )
+                .ok();
+                return Err(e);
+            }
+        };
+
+        let prev_mode = self.current_mode.clone();
+        self.apply_config(&config);
+        // Don't wait for the next retry of a lost connection.
+        if let Some(provider) = &mut self.window_provider {
+            provider.retry();
+        }
+        self.update_auto_mode();
+        if self.current_mode != prev_mode {
+            info!(
+                "Mode {:?} no longer exists, switch to {:?}",
+                prev_mode, self.current_mode
+            );
+        }
+        send_notify(NAME, &format!("{} config is reloaded.", NAME)).ok();
         Ok(())
     }
+
+    fn handle_request(&mut self, request: Request) -> Result<String, Box<dyn Error>> {
+        match request {
+            Request::GetMode => Ok(format!(
+                "{}\n",
+                self.current_mode.as_ref().unwrap_or(&DEFAULT_MODE)
+            )),
+            Request::SetMode(mode) => {
+                if !self.all_modes.contains(&mode) {
+                    return Err(format!("unknown mode '{}'", mode).into());
+                }
+                self.switch_mode(&mode);
+                Ok(String::new())
+            }
+            Request::Reload => self.reload().map(|_| String::new()),
+            Request::Pause => {
+                self.paused = true;
+                send_notify(NAME, &format!("{} is paused.", NAME)).ok();
+                Ok(String::new())
+            }
+            Request::Resume => {
+                self.paused = false;
+                send_notify(NAME, &format!("{} is resumed.", NAME)).ok();
+                Ok(String::new())
+            }
+            Request::Bindings => Ok(self.describe_bindings()),
+        }
+    }
+
+    fn shutdown(&mut self) -> Result<(), Box<dyn Error>> {
+        // Nothing of the undecided tap-hold key is sent yet.
+        self.pending_tap_hold = None;
+        self.pending_sequence = None;
+        self.pending_chord = None;
+        self.chord_keys.clear();
+        self.layers.clear();
+        self.held_actions.clear();
+        self.swallowed_keys.clear();
+        self.one_shot.clear();
+        self.custom_pressed.clear();
+        let keys: Vec<Key> = self.pressed_keys.drain().map(|(_, key)| key).collect();
+        for key in keys.iter() {
+            if !MODIFIER_KEYS.contains(key) {
+                self.send_key(key, RELEASE)?;
+            }
+        }
+        self.reset()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::window::FocusedWindow;
+
+    fn window(class: &str) -> FocusedWindow {
+        FocusedWindow {
+            class: class.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn leader_key_works_for_global_and_app_bindings() {
+        let config: Config = serde_yaml::from_str(
+            r#"
+options:
+  leader_key: Ctrl-KEY_SPACE
+groups:
+  global:
+    key_bindings:
+    - leader: [W, S]
+      shell: split
+  gimp:
+    in: Gimp
+    key_bindings:
+    - leader: [G]
+      shell: gimp-action
+"#,
+        )
+        .unwrap();
+        let (_, lookup_table) = DefaultEventHandler::construct_lookup_table(&config);
+        let sequences = &lookup_table[DEFAULT_MODE.as_str()].sequences;
+        let leader_key = config.leader_key().unwrap().clone();
+        let leader = sequences.get(std::slice::from_ref(&leader_key)).unwrap();
+
+        // The global binding keeps the leader key working outside Gimp.
+        let other = window("Alacritty");
+        assert!(leader.is_prefix_for(|x| x.is_match(Some(&other))));
+        let gimp = window("Gimp");
+        assert!(leader.is_prefix_for(|x| x.is_match(Some(&gimp))));
+
+        let app_only = sequences
+            .get(&[leader_key, serde_yaml::from_str("G").unwrap()])
+            .and_then(|x| x.binding.as_ref())
+            .unwrap();
+        assert!(app_only.condition.is_match(Some(&gimp)));
+        assert!(!app_only.condition.is_match(Some(&other)));
+    }
 }
@@ -1,7 +1,9 @@
+use std::collections::HashSet;
 use std::time::UNIX_EPOCH;
 
 use evdev::EventType;
 use evdev::InputEvent;
+use evdev::KeyCode as Key;
 use evdev::uinput::VirtualDevice;
 use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
 
@@ -11,6 +13,8 @@ use crate::output::build_device;
 pub struct EchoEventHandler {
     echo_stream: StandardStream,
     output_device: VirtualDevice,
+    /// Keys pressed on the output device
+    pressed_keys: HashSet<Key>,
 }
 
 impl EchoEventHandler {
@@ -22,6 +26,7 @@ impl EchoEventHandler {
         Ok(Self {
             output_device,
             echo_stream,
+            pressed_keys: HashSet::new(),
         })
     }
 
@@ -57,7 +62,19 @@ impl EventHandler for EchoEventHandler {
         }
 
         self.echo_stream.reset()?;
+        match event.value() {
+            0 => self.pressed_keys.remove(&Key::new(event.code())),
+            _ => self.pressed_keys.insert(Key::new(event.code())),
+        };
         self.send_event(event)?;
         Ok(())
     }
+
+    fn shutdown(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        for key in self.pressed_keys.drain() {
+            let event = InputEvent::new(EventType::KEY.0, key.code(), 0);
+            self.output_device.emit(&[event])?;
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,349 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime};
+
+use evdev::KeyCode as Key;
+use log::warn;
+
+use crate::config::{Action, KeyCombo, TriggerOn, WindowMatcher};
+use crate::window::FocusedWindow;
+
+/// Application conditions of a group, empty means always.
+#[derive(Debug, Clone, Default)]
+pub struct Condition {
+    pub in_: Vec<WindowMatcher>,
+    pub not_in: Vec<WindowMatcher>,
+}
+
+impl Condition {
+    pub fn is_empty(&self) -> bool {
+        self.in_.is_empty() && self.not_in.is_empty()
+    }
+
+    /// An unknown window matches no `in` and no `notin`.
+    pub fn is_match(&self, window: Option<&FocusedWindow>) -> bool {
+        let matches = |matchers: &Vec<WindowMatcher>| {
+            window.is_some_and(|w| matchers.iter().any(|m| m.is_match(w)))
+        };
+        if !self.in_.is_empty() && !matches(&self.in_) {
+            return false;
+        }
+        if !self.not_in.is_empty() && matches(&self.not_in) {
+            return false;
+        }
+        true
+    }
+}
+
+/// The inner struct for match keybinding.
+#[derive(Debug)]
+pub struct KeyMatchStruct {
+    pub condition: Condition,
+    pub action: Action,
+    pub on: TriggerOn,
+    pub desc: Option<String>,
+}
+
+/// Bindings of a mode.
+#[derive(Debug, Default)]
+pub struct Keymap {
+    pub sequences: KeyNode,
+    pub chords: Vec<Chord>,
+    pub multi_taps: Vec<MultiTap>,
+}
+
+/// A key combo tapped several times in a row.
+#[derive(Debug)]
+pub struct MultiTap {
+    pub key_combo: KeyCombo,
+    pub taps: usize,
+    pub binding: KeyMatchStruct,
+}
+
+/// Keys pressed together, in any order.
+#[derive(Debug)]
+pub struct Chord {
+    pub keys: Vec<Key>,
+    pub binding: KeyMatchStruct,
+}
+
+impl Chord {
+    /// All of `keys` belong to the chord.
+    pub fn contains_all(&self, keys: &[Key]) -> bool {
+        keys.iter().all(|x| self.keys.contains(x))
+    }
+
+    /// Exactly the keys of the chord are pressed.
+    pub fn is_complete(&self, keys: &[Key]) -> bool {
+        keys.len() == self.keys.len() && self.contains_all(keys)
+    }
+}
+
+/// A node of the key sequence trie, the root holds all bindings of a mode.
+#[derive(Debug, Default)]
+pub struct KeyNode {
+    /// The binding that ends here
+    pub binding: Option<KeyMatchStruct>,
+    /// Conditions of the groups that added the longer bindings, the prefix is entered if any
+    /// of them matches
+    pub conditions: Vec<Condition>,
+    pub children: HashMap<KeyCombo, KeyNode>,
+}
+
+impl KeyNode {
+    /// A prefix of longer bindings, it waits for the next key combo.
+    pub fn is_prefix(&self) -> bool {
+        !self.children.is_empty()
+    }
+
+    /// Insert a binding, the later one wins if the same sequence is bound twice.
+    pub fn insert(&mut self, combos: &[KeyCombo], binding: KeyMatchStruct) {
+        self.insert_at(combos, 0, binding);
+    }
+
+    fn insert_at(&mut self, combos: &[KeyCombo], depth: usize, binding: KeyMatchStruct) {
+        let Some(combo) = combos.get(depth) else {
+            return;
+        };
+        let node = self.children.entry(combo.clone()).or_default();
+        if depth + 1 == combos.len() {
+            if node.is_prefix() {
+                warn!(
+                    "`{}` is a prefix of longer key sequences, it never fires",
+                    format_sequence(combos)
+                );
+            }
+            node.binding = Some(binding);
+        } else {
+            if node.binding.is_some() {
+                warn!(
+                    "`{}` is a prefix of `{}`, it never fires",
+                    format_sequence(&combos[..=depth]),
+                    format_sequence(combos)
+                );
+            }
+            node.conditions.push(binding.condition.clone());
+            node.insert_at(combos, depth + 1, binding);
+        }
+    }
+
+    /// Some longer binding applies to the window, so the prefix waits for the next key combo.
+    pub fn is_prefix_for<F>(&self, is_match: F) -> bool
+    where
+        F: FnMut(&Condition) -> bool,
+    {
+        self.is_prefix() && self.conditions.iter().any(is_match)
+    }
+
+    /// The node reached by typing `combos` from here.
+    pub fn get(&self, combos: &[KeyCombo]) -> Option<&KeyNode> {
+        let mut node = self;
+        for combo in combos.iter() {
+            node = node.children.get(combo)?;
+        }
+        Some(node)
+    }
+
+    /// The keys that may follow, one per line, e.g. `KEY_S  split window`.
+    pub fn describe_children(&self) -> Vec<String> {
+        let mut lines: Vec<String> = self
+            .children
+            .iter()
+            .map(|(combo, node)| match &node.binding {
+                _ if node.is_prefix() => format!("{}  +{}", combo, node.children.len()),
+                Some(s) => match &s.desc {
+                    Some(desc) => format!("{}  {}", combo, desc),
+                    None => format!("{}  {}", combo, s.action),
+                },
+                None => combo.to_string(),
+            })
+            .collect();
+        lines.sort();
+        lines
+    }
+
+    /// Visit all bindings under this node, with their full sequence.
+    pub fn walk<F>(&self, prefix: &mut Vec<KeyCombo>, f: &mut F)
+    where
+        F: FnMut(&[KeyCombo], &KeyMatchStruct),
+    {
+        if let Some(binding) = &self.binding {
+            f(prefix, binding);
+        }
+        for (combo, node) in self.children.iter() {
+            prefix.push(combo.clone());
+            node.walk(prefix, f);
+            prefix.pop();
+        }
+    }
+}
+
+/// e.g. `Ctrl-KEY_X Ctrl-KEY_F`
+pub fn format_sequence(combos: &[KeyCombo]) -> String {
+    combos
+        .iter()
+        .map(|x| x.to_string())
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// Counts the taps of the same key in a row, from the event timestamps.
+#[derive(Debug, Default)]
+pub struct TapCounter {
+    key: Option<Key>,
+    count: usize,
+    released_at: Option<SystemTime>,
+}
+
+impl TapCounter {
+    /// Returns which tap in a row this press is, starting from 1.
+    pub fn press(&mut self, key: Key, time: SystemTime, interval: Duration) -> usize {
+        let again = self.key == Some(key)
+            && self
+                .released_at
+                .and_then(|x| time.duration_since(x).ok())
+                .is_some_and(|x| x <= interval);
+        self.count = if again { self.count + 1 } else { 1 };
+        self.key = Some(key);
+        self.released_at = None;
+        self.count
+    }
+
+    pub fn release(&mut self, key: Key, time: SystemTime) {
+        if self.key == Some(key) {
+            self.released_at = Some(time);
+        }
+    }
+}
+
+/// Keys of a chord pressed so far, they are held back until it completes.
+#[derive(Debug)]
+pub struct PendingChord {
+    pub keys: Vec<Key>,
+    /// The chord fails, or fires if complete, at this moment
+    pub deadline: Instant,
+}
+
+/// The prefix of a key sequence typed so far.
+#[derive(Debug)]
+pub struct PendingSequence {
+    /// Swallowed key combos, replayed if the sequence doesn't match
+    pub combos: Vec<KeyCombo>,
+    /// Key combos of the bindings they matched, the path in the trie
+    pub path: Vec<KeyCombo>,
+    /// The prefix is cancelled at this moment
+    pub deadline: Instant,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn combo(s: &str) -> KeyCombo {
+        serde_yaml::from_str(s).unwrap()
+    }
+
+    fn binding(condition: Condition) -> KeyMatchStruct {
+        KeyMatchStruct {
+            condition,
+            action: Action::Shell("true".to_string()),
+            on: TriggerOn::Press,
+            desc: None,
+        }
+    }
+
+    fn window(class: &str) -> FocusedWindow {
+        FocusedWindow {
+            class: class.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn in_gimp() -> Condition {
+        Condition {
+            in_: vec![serde_yaml::from_str("Gimp").unwrap()],
+            not_in: vec![],
+        }
+    }
+
+    #[test]
+    fn shared_prefix_keeps_all_conditions() {
+        let mut root = KeyNode::default();
+        root.insert(
+            &[combo("Ctrl-X"), combo("Ctrl-F")],
+            binding(Condition::default()),
+        );
+        root.insert(&[combo("Ctrl-X"), combo("Ctrl-S")], binding(in_gimp()));
+
+        let prefix = root.get(&[combo("Ctrl-X")]).unwrap();
+        for class in ["Alacritty", "Gimp"] {
+            let focused = window(class);
+            assert!(
+                prefix.is_prefix_for(|x| x.is_match(Some(&focused))),
+                "{}",
+                class
+            );
+        }
+
+        let in_gimp_only = root.get(&[combo("Ctrl-X"), combo("Ctrl-S")]).unwrap();
+        assert!(
+            !in_gimp_only
+                .binding
+                .as_ref()
+                .unwrap()
+                .condition
+                .is_match(Some(&window("Alacritty")))
+        );
+    }
+
+    #[test]
+    fn unknown_window_fails_in_and_passes_notin() {
+        assert!(!in_gimp().is_match(None));
+        let not_in_gimp = Condition {
+            in_: vec![],
+            not_in: in_gimp().in_,
+        };
+        assert!(not_in_gimp.is_match(None));
+    }
+
+    #[test]
+    fn conditioned_prefix_is_skipped_elsewhere() {
+        let mut root = KeyNode::default();
+        root.insert(&[combo("Ctrl-X"), combo("Ctrl-S")], binding(in_gimp()));
+
+        let prefix = root.get(&[combo("Ctrl-X")]).unwrap();
+        let gimp = window("Gimp");
+        let other = window("Alacritty");
+        assert!(prefix.is_prefix_for(|x| x.is_match(Some(&gimp))));
+        assert!(!prefix.is_prefix_for(|x| x.is_match(Some(&other))));
+    }
+
+    #[test]
+    fn tap_counter_counts_within_interval() {
+        let interval = Duration::from_millis(300);
+        let t0 = SystemTime::UNIX_EPOCH;
+        let ms = |x| t0 + Duration::from_millis(x);
+        let mut counter = TapCounter::default();
+        assert_eq!(counter.press(Key::KEY_LEFTSHIFT, ms(0), interval), 1);
+        counter.release(Key::KEY_LEFTSHIFT, ms(50));
+        assert_eq!(counter.press(Key::KEY_LEFTSHIFT, ms(350), interval), 2);
+        counter.release(Key::KEY_LEFTSHIFT, ms(400));
+        // The pause counts from the release, not the press.
+        assert_eq!(counter.press(Key::KEY_LEFTSHIFT, ms(701), interval), 1);
+    }
+
+    #[test]
+    fn tap_counter_restarts_after_other_key() {
+        let interval = Duration::from_millis(300);
+        let t0 = SystemTime::UNIX_EPOCH;
+        let ms = |x| t0 + Duration::from_millis(x);
+        let mut counter = TapCounter::default();
+        assert_eq!(counter.press(Key::KEY_LEFTSHIFT, ms(0), interval), 1);
+        counter.release(Key::KEY_LEFTSHIFT, ms(50));
+        assert_eq!(counter.press(Key::KEY_A, ms(100), interval), 1);
+        counter.release(Key::KEY_A, ms(120));
+        assert_eq!(counter.press(Key::KEY_LEFTSHIFT, ms(150), interval), 1);
+
+        // Still held, a press without a release in between is not a tap.
+        assert_eq!(counter.press(Key::KEY_LEFTSHIFT, ms(200), interval), 1);
+    }
+}
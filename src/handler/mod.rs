@@ -1,10 +1,58 @@
 mod default;
 mod echo;
+mod keymap;
+mod one_shot;
+mod tap_hold;
+
+use std::os::fd::RawFd;
+use std::time::Instant;
 
 pub use default::*;
 pub use echo::EchoEventHandler;
 use evdev::InputEvent;
 
+use crate::control::Request;
+
+// The value of InputEvent
+const RELEASE: i32 = 0;
+const PRESS: i32 = 1;
+const REPEAT: i32 = 2;
+
 pub trait EventHandler {
     fn handle_event(&mut self, event: InputEvent) -> Result<(), Box<dyn std::error::Error>>;
+
+    /// When `handle_timeout` should be called, None if nothing is waiting for a timer.
+    fn next_deadline(&self) -> Option<Instant> {
+        None
+    }
+
+    /// Called by the event loop once `next_deadline` is reached.
+    fn handle_timeout(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    /// An fd that tells about the focused window, e.g. the X connection.
+    fn window_fd(&self) -> Option<RawFd> {
+        None
+    }
+
+    /// Called by the event loop once `window_fd` is readable.
+    fn handle_window_event(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    /// Reload the config, the old one is kept if the new one is invalid.
+    fn reload(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    /// Serve a request from the control socket, returns the response payload.
+    fn handle_request(&mut self, request: Request) -> Result<String, Box<dyn std::error::Error>> {
+        Err(format!("{:?} is not supported", request).into())
+    }
+
+    /// Called once before the event loop exits, release everything still pressed.
+    fn shutdown(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
 }
@@ -0,0 +1,193 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use evdev::KeyCode as Key;
+
+use crate::config::{KeyCombo, Modifier, OneShot};
+use crate::keycode::*;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum State {
+    /// Applies to the next key, if it comes before the deadline
+    Armed(Instant),
+    /// Applies to all keys until tapped again
+    Locked,
+}
+
+/// Sticky modifiers, a tapped modifier applies to the next non-modifier key.
+#[derive(Debug, Default)]
+pub struct OneShotModifiers {
+    modifiers: Vec<Modifier>,
+    timeout: Duration,
+    states: HashMap<Modifier, State>,
+    /// The modifier key pressed last, it is a tap if released before any other key
+    candidate: Option<Key>,
+}
+
+impl OneShotModifiers {
+    pub fn new(one_shot: Option<&OneShot>) -> Self {
+        match one_shot {
+            Some(one_shot) => Self {
+                modifiers: one_shot.modifiers.clone(),
+                timeout: Duration::from_millis(one_shot.timeout),
+                ..Default::default()
+            },
+            None => Self::default(),
+        }
+    }
+
+    pub fn press(&mut self, key: Key) {
+        self.candidate = modifier_of(key).map(|_| key);
+    }
+
+    /// Arm the modifier if the key is tapped, tap an armed one again to lock it.
+    pub fn release(&mut self, key: Key, now: Instant) {
+        if self.candidate.take() != Some(key) {
+            return;
+        }
+        let Some(modifier) = modifier_of(key) else {
+            return;
+        };
+        if !self.modifiers.contains(&modifier) {
+            return;
+        }
+        match self.states.get(&modifier) {
+            Some(State::Armed(deadline)) if now < *deadline => {
+                self.states.insert(modifier, State::Locked);
+            }
+            Some(State::Locked) => {
+                self.states.remove(&modifier);
+            }
+            _ => {
+                self.states
+                    .insert(modifier, State::Armed(now + self.timeout));
+            }
+        }
+    }
+
+    /// Fold the active modifiers into the key combo, armed ones are used up.
+    /// Returns whether any modifier was added.
+    pub fn apply(&mut self, combo: &mut KeyCombo, now: Instant) -> bool {
+        let mut applied = false;
+        for (modifier, state) in self.states.iter() {
+            if let State::Armed(deadline) = state
+                && now >= *deadline
+            {
+                continue;
+            }
+            let pressed = match modifier {
+                Modifier::Shift => &mut combo.shift,
+                Modifier::Control => &mut combo.control,
+                Modifier::Alt => &mut combo.alt,
+                Modifier::Windows => &mut combo.windows,
+            };
+            applied |= !*pressed;
+            *pressed = true;
+        }
+        self.states.retain(|_, state| *state == State::Locked);
+        applied
+    }
+
+    pub fn clear(&mut self) {
+        self.states.clear();
+        self.candidate = None;
+    }
+}
+
+fn modifier_of(key: Key) -> Option<Modifier> {
+    if SHIFT_KEYS.contains(&key) {
+        Some(Modifier::Shift)
+    } else if CONTROL_KEYS.contains(&key) {
+        Some(Modifier::Control)
+    } else if ALT_KEYS.contains(&key) {
+        Some(Modifier::Alt)
+    } else if WINDOWS_KEYS.contains(&key) {
+        Some(Modifier::Windows)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn one_shot() -> OneShotModifiers {
+        OneShotModifiers::new(Some(&OneShot {
+            modifiers: vec![Modifier::Shift, Modifier::Control],
+            timeout: 1000,
+        }))
+    }
+
+    fn tap(one_shot: &mut OneShotModifiers, key: Key, now: Instant) {
+        one_shot.press(key);
+        one_shot.release(key, now);
+    }
+
+    /// Apply to the next key, returns whether Shift was added.
+    fn shifted(one_shot: &mut OneShotModifiers, now: Instant) -> bool {
+        let mut combo: KeyCombo = serde_yaml::from_str("KEY_A").unwrap();
+        one_shot.apply(&mut combo, now) && combo.shift
+    }
+
+    #[test]
+    fn arm_applies_once() {
+        let now = Instant::now();
+        let mut one_shot = one_shot();
+        tap(&mut one_shot, Key::KEY_LEFTSHIFT, now);
+        assert!(shifted(&mut one_shot, now));
+        assert!(!shifted(&mut one_shot, now));
+    }
+
+    #[test]
+    fn held_modifier_is_not_armed() {
+        let now = Instant::now();
+        let mut one_shot = one_shot();
+        // Shift-A typed as usual, another key comes before the release.
+        one_shot.press(Key::KEY_LEFTSHIFT);
+        one_shot.press(Key::KEY_A);
+        one_shot.release(Key::KEY_LEFTSHIFT, now);
+        assert!(!shifted(&mut one_shot, now));
+    }
+
+    #[test]
+    fn unlisted_modifier_is_not_armed() {
+        let now = Instant::now();
+        let mut one_shot = one_shot();
+        tap(&mut one_shot, Key::KEY_LEFTALT, now);
+        let mut combo: KeyCombo = serde_yaml::from_str("KEY_A").unwrap();
+        assert!(!one_shot.apply(&mut combo, now));
+    }
+
+    #[test]
+    fn double_tap_locks_and_third_unlocks() {
+        let now = Instant::now();
+        let mut one_shot = one_shot();
+        tap(&mut one_shot, Key::KEY_LEFTSHIFT, now);
+        tap(&mut one_shot, Key::KEY_LEFTSHIFT, now);
+        assert!(shifted(&mut one_shot, now));
+        assert!(shifted(&mut one_shot, now));
+
+        tap(&mut one_shot, Key::KEY_LEFTSHIFT, now);
+        assert!(!shifted(&mut one_shot, now));
+    }
+
+    #[test]
+    fn armed_modifier_times_out() {
+        let now = Instant::now();
+        let mut one_shot = one_shot();
+        tap(&mut one_shot, Key::KEY_LEFTSHIFT, now);
+        assert!(!shifted(&mut one_shot, now + Duration::from_millis(1000)));
+
+        // A second tap after the timeout arms again instead of locking.
+        tap(&mut one_shot, Key::KEY_LEFTSHIFT, now);
+        tap(
+            &mut one_shot,
+            Key::KEY_LEFTSHIFT,
+            now + Duration::from_millis(1000),
+        );
+        let later = now + Duration::from_millis(1500);
+        assert!(shifted(&mut one_shot, later));
+        assert!(!shifted(&mut one_shot, later));
+    }
+}
@@ -0,0 +1,142 @@
+use std::time::{Duration, Instant};
+
+use evdev::InputEvent;
+use evdev::KeyCode as Key;
+
+use super::{PRESS, RELEASE};
+use crate::config::{TapHold, TapHoldStrategy};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Decision {
+    Tap,
+    Hold,
+}
+
+/// A tap-hold key that is pressed, but not decided as tap or hold yet.
+#[derive(Debug)]
+pub struct PendingTapHold {
+    /// The physical key
+    pub key: Key,
+    pub tap_hold: TapHold,
+    /// Hold if the key is still pressed at this moment
+    pub deadline: Instant,
+    /// Key events received while undecided, replayed after the decision
+    pub buffer: Vec<InputEvent>,
+}
+
+impl PendingTapHold {
+    pub fn new(key: Key, tap_hold: TapHold, now: Instant) -> Self {
+        let deadline = now + Duration::from_millis(tap_hold.timeout);
+        Self {
+            key,
+            tap_hold,
+            deadline,
+            buffer: vec![],
+        }
+    }
+
+    /// Still pressed at the deadline, it's a hold.
+    pub fn is_expired(&self, now: Instant) -> bool {
+        now >= self.deadline
+    }
+
+    /// Feed a key event received before the deadline, returns the decision if it can be made.
+    pub fn feed(&mut self, event: InputEvent) -> Option<Decision> {
+        let key = Key::new(event.code());
+        if key == self.key {
+            // Released in time, it's a tap. Repeat is ignored.
+            return match event.value() {
+                RELEASE => Some(Decision::Tap),
+                _ => None,
+            };
+        }
+
+        self.buffer.push(event);
+        match self.tap_hold.strategy {
+            TapHoldStrategy::HoldOnOtherKeyPress if event.value() == PRESS => Some(Decision::Hold),
+            TapHoldStrategy::PermissiveHold if event.value() == RELEASE => {
+                // Another key is pressed and released inside the tap-hold key.
+                let pressed_inside = self
+                    .buffer
+                    .iter()
+                    .any(|e| e.code() == event.code() && e.value() == PRESS);
+                pressed_inside.then_some(Decision::Hold)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use evdev::EventType;
+
+    use super::*;
+
+    const TAP_HOLD: Key = Key::KEY_CAPSLOCK;
+
+    fn pending(strategy: TapHoldStrategy, now: Instant) -> PendingTapHold {
+        let tap_hold = TapHold {
+            tap: Key::KEY_ESC,
+            hold: Key::KEY_LEFTCTRL,
+            timeout: 200,
+            strategy,
+        };
+        PendingTapHold::new(TAP_HOLD, tap_hold, now)
+    }
+
+    fn event(key: Key, value: i32) -> InputEvent {
+        InputEvent::new(EventType::KEY.0, key.code(), value)
+    }
+
+    #[test]
+    fn tap() {
+        for strategy in [
+            TapHoldStrategy::Timeout,
+            TapHoldStrategy::PermissiveHold,
+            TapHoldStrategy::HoldOnOtherKeyPress,
+        ] {
+            let mut pending = pending(strategy, Instant::now());
+            assert_eq!(pending.feed(event(TAP_HOLD, 2)), None);
+            assert_eq!(pending.feed(event(TAP_HOLD, RELEASE)), Some(Decision::Tap));
+        }
+    }
+
+    #[test]
+    fn hold_on_timeout() {
+        let now = Instant::now();
+        let mut pending = pending(TapHoldStrategy::Timeout, now);
+        // Other keys are only delayed.
+        assert_eq!(pending.feed(event(Key::KEY_A, PRESS)), None);
+        assert_eq!(pending.feed(event(Key::KEY_A, RELEASE)), None);
+        assert_eq!(pending.buffer.len(), 2);
+        assert!(!pending.is_expired(now + Duration::from_millis(199)));
+        assert!(pending.is_expired(now + Duration::from_millis(200)));
+    }
+
+    #[test]
+    fn permissive_hold() {
+        let mut pending = pending(TapHoldStrategy::PermissiveHold, Instant::now());
+        assert_eq!(pending.feed(event(Key::KEY_A, PRESS)), None);
+        assert_eq!(
+            pending.feed(event(Key::KEY_A, RELEASE)),
+            Some(Decision::Hold)
+        );
+    }
+
+    #[test]
+    fn permissive_hold_ignores_key_pressed_before() {
+        // A was pressed before the tap-hold key, its release inside doesn't make a hold.
+        let mut pending = pending(TapHoldStrategy::PermissiveHold, Instant::now());
+        assert_eq!(pending.feed(event(Key::KEY_A, RELEASE)), None);
+        assert_eq!(pending.feed(event(TAP_HOLD, RELEASE)), Some(Decision::Tap));
+    }
+
+    #[test]
+    fn hold_on_other_key_press() {
+        let mut pending = pending(TapHoldStrategy::HoldOnOtherKeyPress, Instant::now());
+        assert_eq!(pending.feed(event(Key::KEY_A, RELEASE)), None);
+        assert_eq!(pending.feed(event(Key::KEY_B, PRESS)), Some(Decision::Hold));
+        assert_eq!(pending.buffer.len(), 2);
+    }
+}
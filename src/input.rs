@@ -1,93 +1,385 @@
 use std::error::Error;
-use std::os::fd::BorrowedFd;
-use std::os::unix::io::AsRawFd;
+use std::ffi::OsString;
+use std::io::{ErrorKind, Read};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
 use std::sync::Arc;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering;
+use std::time::Instant;
 
 use evdev::Device;
-use log::info;
+use log::{debug, info, warn};
 use nix::errno::Errno;
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
 use nix::sys::select::FdSet;
 use nix::sys::select::select;
+use nix::sys::time::{TimeVal, TimeValLike};
+use signal_hook::SigId;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
 
 use super::handler::EventHandler;
+use crate::control::ControlServer;
+use crate::device::{DeviceManager, DeviceSelector, INPUT_DEVICE_PATH};
+
+/// Watch `/dev/input` for devices plugged in while running.
+struct Hotplug {
+    inotify: Inotify,
+    /// Only grab the new device if it's selected
+    selector: DeviceSelector,
+}
+
+/// Watch the directory of the config file, editors often replace the file by renaming.
+struct ConfigWatch {
+    inotify: Inotify,
+    file_name: OsString,
+}
 
 /// Main EventLoop, receive device events and call the event_handler to process them.
 pub struct EventLoop<'a> {
-    /// List of devices to listen to
-    input_devices: Vec<Device>,
+    /// List of devices to listen to, with their path
+    input_devices: Vec<(String, Device)>,
     /// Callback handler
     event_handler: Box<dyn EventHandler + 'a>,
-    /// Stop Flag
+    /// Stop Flag, set by SIGINT and SIGTERM
     stop_flag: Arc<AtomicBool>,
-    // TODO Reload?
+    /// Reload Flag, set by SIGHUP
+    reload_flag: Arc<AtomicBool>,
+    /// Self-pipe, signal handlers write to it so that `select` wakes up at once
+    signal_pipe: UnixStream,
+    /// Registered signal handlers, unregistered on drop
+    signal_ids: Vec<SigId>,
+    /// Hotplug watcher, disabled if None
+    hotplug: Option<Hotplug>,
+    /// Config file watcher, disabled if None
+    config_watch: Option<ConfigWatch>,
+    /// Control socket, disabled if None
+    control: Option<ControlServer>,
 }
 
 impl<'a> EventLoop<'a> {
     pub fn new(
-        input_devices: Vec<Device>,
+        input_devices: Vec<(String, Device)>,
         event_handler: Box<dyn EventHandler + 'a>,
-        stop_flag: Arc<AtomicBool>,
     ) -> Result<Self, Box<dyn Error>> {
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let reload_flag = Arc::new(AtomicBool::new(false));
+        let (signal_pipe, signal_pipe_write) = UnixStream::pair()?;
+        signal_pipe.set_nonblocking(true)?;
+
+        let mut signal_ids = vec![];
+        for (signal, flag) in [
+            (SIGINT, &stop_flag),
+            (SIGTERM, &stop_flag),
+            (SIGHUP, &reload_flag),
+        ] {
+            // Set the flag first, then wake up.
+            signal_ids.push(signal_hook::flag::register(signal, Arc::clone(flag))?);
+            signal_ids.push(signal_hook::low_level::pipe::register(
+                signal,
+                signal_pipe_write.try_clone()?,
+            )?);
+        }
+
         Ok(Self {
             input_devices,
             event_handler,
             stop_flag,
+            reload_flag,
+            signal_pipe,
+            signal_ids,
+            hotplug: None,
+            config_watch: None,
+            control: None,
         })
     }
 
+    /// Serve the control socket, see `control` module for the protocol.
+    pub fn serve_control(&mut self, server: ControlServer) {
+        self.control = Some(server);
+    }
+
+    /// Reload the config when the file is written or replaced.
+    pub fn watch_config(&mut self, config_path: &str) -> Result<(), Box<dyn Error>> {
+        let path = Path::new(config_path);
+        let file_name = path
+            .file_name()
+            .ok_or_else(|| format!("Invalid config path: {}", config_path))?
+            .to_os_string();
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+
+        let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)?;
+        inotify.add_watch(
+            dir,
+            AddWatchFlags::IN_CLOSE_WRITE | AddWatchFlags::IN_MOVED_TO,
+        )?;
+        self.config_watch = Some(ConfigWatch { inotify, file_name });
+        Ok(())
+    }
+
+    /// Returns true if the config file is changed.
+    fn is_config_changed(&mut self) -> Result<bool, Box<dyn Error>> {
+        let Some(config_watch) = &self.config_watch else {
+            return Ok(false);
+        };
+        let events = match config_watch.inotify.read_events() {
+            Ok(events) => events,
+            Err(Errno::EAGAIN) => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(events
+            .iter()
+            .any(|event| event.name.as_ref() == Some(&config_watch.file_name)))
+    }
+
+    fn reload(&mut self) {
+        // A bad config is not fatal, the handler keeps the old one.
+        if let Err(e) = self.event_handler.reload() {
+            warn!("Failed to reload config: {}", e);
+        }
+    }
+
+    /// Grab devices selected by `selector` when they appear in `/dev/input`.
+    pub fn watch_hotplug(&mut self, selector: DeviceSelector) -> Result<(), Box<dyn Error>> {
+        let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)?;
+        // udev creates the node first and fixes the permission later,
+        // so the device may only be readable after IN_ATTRIB.
+        inotify.add_watch(
+            INPUT_DEVICE_PATH,
+            AddWatchFlags::IN_CREATE | AddWatchFlags::IN_ATTRIB,
+        )?;
+        self.hotplug = Some(Hotplug { inotify, selector });
+        Ok(())
+    }
+
     fn grab_devices(&mut self) -> Result<(), Box<dyn Error>> {
-        for device in self.input_devices.iter_mut() {
+        for (_, device) in self.input_devices.iter_mut() {
             device.grab()?
         }
         Ok(())
     }
 
-    pub fn run(&mut self) -> Result<(), Box<dyn Error>> {
-        self.grab_devices()?;
-
-        let mut read_fds = FdSet::new();
-        for device in self.input_devices.iter() {
-            let raw_fd = device.as_raw_fd();
-            // let fd = Box::new(unsafe { BorrowedFd::borrow_raw(raw_fd) });
-            // read_fds.insert(Box::leak(fd));
-            read_fds.insert(unsafe { BorrowedFd::borrow_raw(raw_fd) });
+    /// Release what is still pressed on the output device, and give the keyboards back.
+    fn shutdown(&mut self) {
+        if let Err(e) = self.event_handler.shutdown() {
+            warn!("Failed to release pressed keys: {}", e);
+        }
+        for (path, device) in self.input_devices.iter_mut() {
+            if let Err(e) = device.ungrab() {
+                debug!("Failed to ungrab {}: {}", path, e);
+            }
         }
+    }
 
+    /// Drain the self-pipe, we only need it to wake up.
+    fn drain_signal_pipe(&mut self) -> Result<(), Box<dyn Error>> {
+        let mut buf = [0u8; 64];
         loop {
-            // `select` is a slow syscall, it will return when we receive a signal.
-            // If error is `EINTR`, we need to retry.
-            let res = select(None, &mut read_fds, None, None, None);
-            if let Some(err) = res.err() {
-                if err == Errno::EINTR {
+            match self.signal_pipe.read(&mut buf) {
+                Ok(0) => return Ok(()),
+                Ok(_) => continue,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Open and grab the new devices reported by inotify.
+    fn handle_hotplug(&mut self) -> Result<(), Box<dyn Error>> {
+        let Some(hotplug) = &self.hotplug else {
+            return Ok(());
+        };
+        let events = match hotplug.inotify.read_events() {
+            Ok(events) => events,
+            Err(Errno::EAGAIN) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+
+        for event in events {
+            let Some(name) = event.name else {
+                continue;
+            };
+            if !name.as_bytes().starts_with(b"event") {
+                continue;
+            }
+            let path = format!("{}/{}", INPUT_DEVICE_PATH, name.to_string_lossy());
+            if self.input_devices.iter().any(|(p, _)| p == &path) {
+                continue;
+            }
+
+            let mut device = match Device::open(&path) {
+                Ok(device) => device,
+                Err(e) => {
+                    // Most likely the permission is not ready, wait for IN_ATTRIB.
+                    debug!("Failed to open {}: {}", path, e);
                     continue;
                 }
+            };
+            if !hotplug
+                .selector
+                .accepts_hotplug(&path, &device, &self.input_devices)
+            {
+                continue;
+            }
+            if let Err(e) = device.grab() {
+                warn!("Failed to grab {}: {}", path, e);
+                continue;
+            }
+            info!(
+                "Grab new device {}",
+                DeviceManager::describe(&path, &device)
+            );
+            self.input_devices.push((path, device));
+        }
+        Ok(())
+    }
+
+    pub fn run(&mut self) -> Result<(), Box<dyn Error>> {
+        self.grab_devices()?;
+        let res = self.run_loop();
+        self.shutdown();
+        res
+    }
+
+    fn run_loop(&mut self) -> Result<(), Box<dyn Error>> {
+        let signal_fd = self.signal_pipe.as_raw_fd();
+
+        loop {
+            // `select` overwrites the set with the readable fds, so it must be
+            // rebuilt every time, otherwise idle devices are dropped from it.
+            let mut read_fds = FdSet::new();
+            for (_, device) in self.input_devices.iter() {
+                let raw_fd = device.as_raw_fd();
+                read_fds.insert(unsafe { BorrowedFd::borrow_raw(raw_fd) });
             }
-            // let select_res = self.select_readable_devices();
+            let hotplug_fd = self.hotplug.as_ref().map(|x| x.inotify.as_fd().as_raw_fd());
+            if let Some(fd) = hotplug_fd {
+                read_fds.insert(unsafe { BorrowedFd::borrow_raw(fd) });
+            }
+            let config_watch_fd = self
+                .config_watch
+                .as_ref()
+                .map(|x| x.inotify.as_fd().as_raw_fd());
+            if let Some(fd) = config_watch_fd {
+                read_fds.insert(unsafe { BorrowedFd::borrow_raw(fd) });
+            }
+            let control_fd = self.control.as_ref().map(|x| x.listener().as_raw_fd());
+            if let Some(fd) = control_fd {
+                read_fds.insert(unsafe { BorrowedFd::borrow_raw(fd) });
+            }
+            let window_fd = self.event_handler.window_fd();
+            if let Some(fd) = window_fd {
+                read_fds.insert(unsafe { BorrowedFd::borrow_raw(fd) });
+            }
+            read_fds.insert(unsafe { BorrowedFd::borrow_raw(signal_fd) });
+
+            // Wake up in time if the handler is waiting for a timer, e.g. tap-hold.
+            let deadline = self.event_handler.next_deadline();
+            let mut timeout = deadline.map(|x| {
+                let remaining = x.saturating_duration_since(Instant::now());
+                TimeVal::microseconds(remaining.as_micros() as i64)
+            });
 
-            // Check if it needs to stop, the current implementation is very simple.
-            // Known problems is that we need to press any key to stop after SIGINT.
-            // There are many ways to do this:
-            // 1. A thread to check signal and write a char to pipe fd,
-            // and put this fd to `select`
-            // 2. ...
+            // `select` is a slow syscall, it will return when we receive a signal.
+            let res = select(None, &mut read_fds, None, None, timeout.as_mut());
+
+            // The signal handler sets the flag before writing to the self-pipe,
+            // so either `EINTR` or the readable pipe means the flag is up to date.
             if self.stop_flag.load(Ordering::Relaxed) {
                 info!("Stop now...");
                 return Ok(());
             }
+            if self.reload_flag.swap(false, Ordering::Relaxed) {
+                self.reload();
+            }
+            // If error is `EINTR`, we need to retry.
+            if let Some(err) = res.err()
+                && err == Errno::EINTR
+            {
+                continue;
+            }
 
             let readable_fds = read_fds;
-            for input_device in self.input_devices.iter_mut() {
+            if readable_fds.contains(unsafe { BorrowedFd::borrow_raw(signal_fd) }) {
+                self.drain_signal_pipe()?;
+            }
+
+            // Before the key events, they may depend on the focused window.
+            if let Some(fd) = window_fd
+                && readable_fds.contains(unsafe { BorrowedFd::borrow_raw(fd) })
+            {
+                self.event_handler.handle_window_event()?;
+            }
+
+            let mut removed = vec![];
+            for (idx, (path, input_device)) in self.input_devices.iter_mut().enumerate() {
                 if !readable_fds
                     .contains(unsafe { BorrowedFd::borrow_raw(input_device.as_raw_fd()) })
                 {
                     continue;
                 }
-                for event in input_device.fetch_events()? {
+                let events = match input_device.fetch_events() {
+                    Ok(events) => events,
+                    // The device is unplugged
+                    Err(e) if e.raw_os_error() == Some(libc::ENODEV) => {
+                        info!("Device {} is removed", path);
+                        removed.push(idx);
+                        continue;
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+                for event in events {
                     self.event_handler.handle_event(event)?;
                 }
             }
+            for idx in removed.into_iter().rev() {
+                self.input_devices.remove(idx);
+            }
+
+            if let Some(deadline) = self.event_handler.next_deadline()
+                && Instant::now() >= deadline
+            {
+                self.event_handler.handle_timeout()?;
+            }
+
+            if let Some(fd) = hotplug_fd
+                && readable_fds.contains(unsafe { BorrowedFd::borrow_raw(fd) })
+            {
+                self.handle_hotplug()?;
+            }
+
+            if let Some(fd) = config_watch_fd
+                && readable_fds.contains(unsafe { BorrowedFd::borrow_raw(fd) })
+                && self.is_config_changed()?
+            {
+                self.reload();
+            }
+
+            if let Some(fd) = control_fd
+                && readable_fds.contains(unsafe { BorrowedFd::borrow_raw(fd) })
+                && let Some(control) = &self.control
+            {
+                let event_handler = &mut self.event_handler;
+                control.serve(|request| event_handler.handle_request(request));
+            }
+
+            if self.input_devices.is_empty() && self.hotplug.is_none() {
+                return Err("All devices are removed".into());
+            }
+        }
+    }
+}
+
+impl Drop for EventLoop<'_> {
+    fn drop(&mut self) {
+        for id in self.signal_ids.drain(..) {
+            signal_hook::low_level::unregister(id);
         }
     }
 }
@@ -1,4 +1,4 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
 use evdev::KeyCode as Key;
 use lazy_static::lazy_static;
@@ -32,6 +32,146 @@ lazy_static! {
             .into_iter(),
         )
     };
+    /// A device has to support all of these to be treated as a keyboard.
+    pub static ref KEYBOARD_KEYS: HashSet<Key> = {
+        HashSet::from_iter(
+            [
+                Key::KEY_A,
+                Key::KEY_B,
+                Key::KEY_C,
+                Key::KEY_D,
+                Key::KEY_E,
+                Key::KEY_F,
+                Key::KEY_G,
+                Key::KEY_H,
+                Key::KEY_I,
+                Key::KEY_J,
+                Key::KEY_K,
+                Key::KEY_L,
+                Key::KEY_M,
+                Key::KEY_N,
+                Key::KEY_O,
+                Key::KEY_P,
+                Key::KEY_Q,
+                Key::KEY_R,
+                Key::KEY_S,
+                Key::KEY_T,
+                Key::KEY_U,
+                Key::KEY_V,
+                Key::KEY_W,
+                Key::KEY_X,
+                Key::KEY_Y,
+                Key::KEY_Z,
+                Key::KEY_ENTER,
+            ]
+            .into_iter(),
+        )
+    };
+    /// Printable ASCII on a US layout, the key and whether Shift is needed.
+    pub static ref ASCII_KEYS: HashMap<char, (Key, bool)> = {
+        HashMap::from_iter(
+            [
+                ('a', (Key::KEY_A, false)),
+                ('A', (Key::KEY_A, true)),
+                ('b', (Key::KEY_B, false)),
+                ('B', (Key::KEY_B, true)),
+                ('c', (Key::KEY_C, false)),
+                ('C', (Key::KEY_C, true)),
+                ('d', (Key::KEY_D, false)),
+                ('D', (Key::KEY_D, true)),
+                ('e', (Key::KEY_E, false)),
+                ('E', (Key::KEY_E, true)),
+                ('f', (Key::KEY_F, false)),
+                ('F', (Key::KEY_F, true)),
+                ('g', (Key::KEY_G, false)),
+                ('G', (Key::KEY_G, true)),
+                ('h', (Key::KEY_H, false)),
+                ('H', (Key::KEY_H, true)),
+                ('i', (Key::KEY_I, false)),
+                ('I', (Key::KEY_I, true)),
+                ('j', (Key::KEY_J, false)),
+                ('J', (Key::KEY_J, true)),
+                ('k', (Key::KEY_K, false)),
+                ('K', (Key::KEY_K, true)),
+                ('l', (Key::KEY_L, false)),
+                ('L', (Key::KEY_L, true)),
+                ('m', (Key::KEY_M, false)),
+                ('M', (Key::KEY_M, true)),
+                ('n', (Key::KEY_N, false)),
+                ('N', (Key::KEY_N, true)),
+                ('o', (Key::KEY_O, false)),
+                ('O', (Key::KEY_O, true)),
+                ('p', (Key::KEY_P, false)),
+                ('P', (Key::KEY_P, true)),
+                ('q', (Key::KEY_Q, false)),
+                ('Q', (Key::KEY_Q, true)),
+                ('r', (Key::KEY_R, false)),
+                ('R', (Key::KEY_R, true)),
+                ('s', (Key::KEY_S, false)),
+                ('S', (Key::KEY_S, true)),
+                ('t', (Key::KEY_T, false)),
+                ('T', (Key::KEY_T, true)),
+                ('u', (Key::KEY_U, false)),
+                ('U', (Key::KEY_U, true)),
+                ('v', (Key::KEY_V, false)),
+                ('V', (Key::KEY_V, true)),
+                ('w', (Key::KEY_W, false)),
+                ('W', (Key::KEY_W, true)),
+                ('x', (Key::KEY_X, false)),
+                ('X', (Key::KEY_X, true)),
+                ('y', (Key::KEY_Y, false)),
+                ('Y', (Key::KEY_Y, true)),
+                ('z', (Key::KEY_Z, false)),
+                ('Z', (Key::KEY_Z, true)),
+                ('1', (Key::KEY_1, false)),
+                ('!', (Key::KEY_1, true)),
+                ('2', (Key::KEY_2, false)),
+                ('@', (Key::KEY_2, true)),
+                ('3', (Key::KEY_3, false)),
+                ('#', (Key::KEY_3, true)),
+                ('4', (Key::KEY_4, false)),
+                ('$', (Key::KEY_4, true)),
+                ('5', (Key::KEY_5, false)),
+                ('%', (Key::KEY_5, true)),
+                ('6', (Key::KEY_6, false)),
+                ('^', (Key::KEY_6, true)),
+                ('7', (Key::KEY_7, false)),
+                ('&', (Key::KEY_7, true)),
+                ('8', (Key::KEY_8, false)),
+                ('*', (Key::KEY_8, true)),
+                ('9', (Key::KEY_9, false)),
+                ('(', (Key::KEY_9, true)),
+                ('0', (Key::KEY_0, false)),
+                (')', (Key::KEY_0, true)),
+                ('-', (Key::KEY_MINUS, false)),
+                ('_', (Key::KEY_MINUS, true)),
+                ('=', (Key::KEY_EQUAL, false)),
+                ('+', (Key::KEY_EQUAL, true)),
+                ('[', (Key::KEY_LEFTBRACE, false)),
+                ('{', (Key::KEY_LEFTBRACE, true)),
+                (']', (Key::KEY_RIGHTBRACE, false)),
+                ('}', (Key::KEY_RIGHTBRACE, true)),
+                ('\\', (Key::KEY_BACKSLASH, false)),
+                ('|', (Key::KEY_BACKSLASH, true)),
+                (';', (Key::KEY_SEMICOLON, false)),
+                (':', (Key::KEY_SEMICOLON, true)),
+                ('\'', (Key::KEY_APOSTROPHE, false)),
+                ('"', (Key::KEY_APOSTROPHE, true)),
+                ('`', (Key::KEY_GRAVE, false)),
+                ('~', (Key::KEY_GRAVE, true)),
+                (',', (Key::KEY_COMMA, false)),
+                ('<', (Key::KEY_COMMA, true)),
+                ('.', (Key::KEY_DOT, false)),
+                ('>', (Key::KEY_DOT, true)),
+                ('/', (Key::KEY_SLASH, false)),
+                ('?', (Key::KEY_SLASH, true)),
+                (' ', (Key::KEY_SPACE, false)),
+                ('\t', (Key::KEY_TAB, false)),
+                ('\n', (Key::KEY_ENTER, false)),
+            ]
+            .into_iter(),
+        )
+    };
     pub static ref WINDOWS_KEYS: HashSet<Key> =
         HashSet::from_iter([Key::KEY_LEFTMETA, Key::KEY_RIGHTMETA,].into_iter());
 }
@@ -1,20 +1,21 @@
 mod config;
+mod control;
 mod device;
 mod executor;
 mod handler;
-mod x11;
-use std::cmp::Ordering;
+mod window;
 
-use env_logger;
 mod input;
 mod keycode;
 mod notification;
 mod output;
-use std::sync::Arc;
-use std::sync::atomic::AtomicBool;
+
+use std::path::PathBuf;
 
 use clap::{Parser, Subcommand};
+use device::{DeviceMatcher, DeviceSelector};
 use evdev::Device as EDevice;
+use log::warn;
 
 // Package meta info
 const NAME: &str = env!("CARGO_PKG_NAME");
@@ -27,13 +28,43 @@ enum Commands {
     // TODO install uninstall stop service edit ...
     /// Run the main application
     Run {
-        /// Configuration file path
+        /// Configuration file path, reloaded on SIGHUP
         #[arg(short, long)]
         config: String,
 
-        /// Keyboard devices to grab
+        /// Also reload the configuration when the file changes
+        #[arg(long)]
+        watch_config: bool,
+
+        /// Control socket path, `$XDG_RUNTIME_DIR/xburner.sock` by default
+        #[arg(long)]
+        socket: Option<PathBuf>,
+
+        /// Keyboard devices to grab, can be given multiple times.
+        /// Overrides the `devices` of the configuration file,
+        /// all keyboards are grabbed if neither is given.
+        /// e.g. `/dev/input/event2`, `name:HHKB`, `regex:^Topre`, `id:04fe:0021`,
+        /// `phys:usb-0000:00:14.0-2/input0`, `uniq:<serial>`
         #[arg(short, long)]
-        device: String,
+        device: Vec<DeviceMatcher>,
+
+        /// Don't connect to X11/sway/Hyprland, e.g. on the Linux console.
+        /// Groups with `in` never apply, groups with only `notin` always do.
+        #[arg(long)]
+        no_window_context: bool,
+    },
+
+    /// Send a command to the running application, e.g. `ctl mode normal`
+    ///
+    /// Commands: mode [NAME], reload, pause, resume, bindings
+    Ctl {
+        /// Control socket path, `$XDG_RUNTIME_DIR/xburner.sock` by default
+        #[arg(long)]
+        socket: Option<PathBuf>,
+
+        /// Command and its arguments
+        #[arg(required = true)]
+        command: Vec<String>,
     },
 
     /// List devices information of this computer
@@ -41,16 +72,17 @@ enum Commands {
 
     /// List supported keys reported by the device
     ListKeys {
-        /// Device path
+        /// Device path or matcher, see `run --help`
         #[arg(short, long)]
-        device: String,
+        device: DeviceMatcher,
     },
 
     /// Echo key information that you typed
     Echo {
-        /// Keyboard devices to grab
+        /// Keyboard devices to grab, can be given multiple times, see `run --help`.
+        /// All keyboards are grabbed if not given.
         #[arg(short, long)]
-        device: String,
+        device: Vec<DeviceMatcher>,
     },
 }
 
@@ -71,31 +103,39 @@ struct Args {
     silent: bool,
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+fn main() {
     env_logger::init();
     let args = Args::parse();
 
+    // Print with `Display`, errors like device matching span multiple lines.
+    if let Err(e) = run(&args) {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run(args: &Args) -> Result<(), Box<dyn std::error::Error>> {
     match &args.command {
         Commands::ListDevice {} => {
             println!("Trying to scan all of {}", device::INPUT_DEVICE_PATH);
             let mut devices: Vec<(String, EDevice)> =
                 device::DeviceManager::scan()?.into_iter().collect();
-            devices.sort_by(|item_1, item_2| {
-                let name_1 = &item_1.0;
-                let name_2 = &item_2.0;
-                match name_1.len().cmp(&name_2.len()) {
-                    Ordering::Equal => name_1.cmp(&name_2),
-                    other => other,
-                }
-            });
+            devices.sort_by(|item_1, item_2| device::compare_path(&item_1.0, &item_2.0));
             println!("Available devices:");
             for (path, device) in devices.into_iter() {
-                println!("{:20}: {}", path, device.name().unwrap_or("Unknown Name"));
+                println!("{}", device::DeviceManager::describe(&path, &device));
             }
             return Ok(());
         }
+        Commands::Ctl { socket, command } => {
+            let socket = socket.clone().unwrap_or_else(control::default_socket_path);
+            let payload = control::send_request(&socket, &command.join(" "))?;
+            print!("{}", payload);
+            return Ok(());
+        }
         Commands::ListKeys { device } => {
-            let device = device::DeviceManager::get_device(device)?;
+            let (_, device) =
+                device::DeviceManager::find_devices(std::slice::from_ref(device))?.remove(0);
             let keys = device
                 .supported_keys()
                 .expect("Could not get supported keys from this device");
@@ -112,26 +152,54 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             //    .filter(|d| d.name().unwrap().contains("HHKB"))
             //    .collect::<Vec<EDevice>>();
 
-            let device = device::DeviceManager::get_device(device)?;
+            let selector = if device.is_empty() {
+                DeviceSelector::Keyboards
+            } else {
+                DeviceSelector::Matchers(device.clone())
+            };
+            let devices = device::DeviceManager::select(&selector)?;
             let event_handler = handler::EchoEventHandler::new()?;
 
-            let term = Arc::new(AtomicBool::new(false));
-            signal_hook::flag::register(signal_hook::consts::SIGINT, Arc::clone(&term))?;
-            let mut event_loop =
-                input::EventLoop::new(vec![device], Box::new(event_handler), term)?;
+            let mut event_loop = input::EventLoop::new(devices, Box::new(event_handler))?;
+            event_loop.watch_hotplug(selector)?;
             event_loop.run()?;
         }
-        Commands::Run { config, device } => {
+        Commands::Run {
+            config: config_path,
+            watch_config,
+            socket,
+            device,
+            no_window_context,
+        } => {
             // Load user config
-            let config = config::Config::load_from_file(config)?;
-
-            let device = device::DeviceManager::get_device(device)?;
-            let event_handler = handler::DefaultEventHandler::new(&config)?;
-
-            let term = Arc::new(AtomicBool::new(false));
-            signal_hook::flag::register(signal_hook::consts::SIGINT, Arc::clone(&term))?;
-            let mut event_loop =
-                input::EventLoop::new(vec![device], Box::new(event_handler), term)?;
+            let config = config::Config::load_from_file(config_path)?;
+
+            // Devices from command line take precedence over the config file.
+            let matchers = if device.is_empty() {
+                config.devices.clone().unwrap_or_default()
+            } else {
+                device.clone()
+            };
+            let selector = if matchers.is_empty() {
+                DeviceSelector::Keyboards
+            } else {
+                DeviceSelector::Matchers(matchers)
+            };
+            let devices = device::DeviceManager::select(&selector)?;
+            let event_handler =
+                handler::DefaultEventHandler::new(config_path, &config, !*no_window_context)?;
+
+            let mut event_loop = input::EventLoop::new(devices, Box::new(event_handler))?;
+            event_loop.watch_hotplug(selector)?;
+            if *watch_config {
+                event_loop.watch_config(config_path)?;
+            }
+            // Remapping still works without the control socket.
+            let socket = socket.clone().unwrap_or_else(control::default_socket_path);
+            match control::ControlServer::bind(socket) {
+                Ok(server) => event_loop.serve_control(server),
+                Err(e) => warn!("Failed to create control socket: {}", e),
+            }
 
             // Send start notification, silent if we meet error.
             notification::send_notify(
@@ -140,9 +208,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             )
             .ok();
 
-            event_loop.run()?;
+            let res = event_loop.run();
 
+            // Notify even if we stop because of an error.
             notification::send_notify(NAME, &format!("{} is stopped now.", NAME)).ok();
+            res?;
         }
     }
     Ok(())
@@ -28,7 +28,7 @@ pub fn build_device() -> Result<VirtualDevice, Box<dyn Error>> {
     for code in Key::KEY_RESERVED.code()..Key::BTN_TRIGGER_HAPPY40.code() {
         let key = Key::new(code);
         let name = format!("{:?}", key);
-        if name.starts_with("KEY_") || MOUSE_BTNS.contains(&&**&name) {
+        if name.starts_with("KEY_") || MOUSE_BTNS.contains(&name.as_str()) {
             keys.insert(key);
         }
     }
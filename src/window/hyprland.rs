@@ -0,0 +1,281 @@
+use std::error::Error;
+use std::ffi::OsStr;
+use std::io::{ErrorKind, Read, Write};
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+use serde::Deserialize;
+
+use super::{FocusedWindow, Retry, WindowProvider};
+
+// Hyprland has two sockets: requests go to `.socket.sock`, one per connection, and
+// `.socket2.sock` streams events as lines of `EVENT>>DATA`.
+
+/// A request must not block for long.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The reply of `j/activewindow`, `{}` if no window is focused.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct ActiveWindow {
+    class: String,
+    title: String,
+    initial_class: String,
+}
+
+/// Where the sockets of the given instance are, `$XDG_RUNTIME_DIR/hypr` since Hyprland 0.40.
+pub fn socket_dir(signature: &OsStr) -> PathBuf {
+    if let Some(runtime_dir) = std::env::var_os("XDG_RUNTIME_DIR") {
+        let dir = Path::new(&runtime_dir).join("hypr").join(signature);
+        if dir.exists() {
+            return dir;
+        }
+    }
+    Path::new("/tmp/hypr").join(signature)
+}
+
+/// Tracks the focused window through the events of Hyprland, and asks for the details on
+/// focus and title changes. Hyprland has no instance and role: the instance is the class
+/// the window was opened with, the role is empty.
+pub struct HyprlandClient {
+    event_path: PathBuf,
+    request_path: PathBuf,
+    stream: UnixStream,
+    /// Received bytes not making a whole line yet
+    buffer: Vec<u8>,
+    focused: Option<FocusedWindow>,
+    /// Set once the connection is broken and reconnecting failed
+    lost: Option<Retry>,
+}
+
+impl HyprlandClient {
+    /// Connect to the event socket, `request_path` is queried for the focused window.
+    pub fn new(event_path: &Path, request_path: &Path) -> Result<Self, Box<dyn Error>> {
+        let stream = Self::connect(event_path)?;
+        let mut client = Self {
+            event_path: event_path.to_path_buf(),
+            request_path: request_path.to_path_buf(),
+            stream,
+            buffer: vec![],
+            focused: None,
+            lost: None,
+        };
+        client.focused = client.query_focused()?;
+        debug!("Focused window => {:?}", client.focused);
+        Ok(client)
+    }
+
+    fn connect(path: &Path) -> Result<UnixStream, Box<dyn Error>> {
+        let stream = UnixStream::connect(path)
+            .map_err(|e| format!("Failed to connect to Hyprland at {}: {}", path.display(), e))?;
+        stream.set_nonblocking(true)?;
+        Ok(stream)
+    }
+
+    fn query_focused(&self) -> Result<Option<FocusedWindow>, Box<dyn Error>> {
+        let mut stream = UnixStream::connect(&self.request_path)?;
+        stream.set_read_timeout(Some(REPLY_TIMEOUT))?;
+        stream.write_all(b"j/activewindow")?;
+        let mut reply = vec![];
+        stream.read_to_end(&mut reply)?;
+        let window: ActiveWindow = serde_json::from_slice(&reply)?;
+        if window.class.is_empty() && window.title.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(FocusedWindow {
+            class: window.class,
+            instance: window.initial_class,
+            title: window.title,
+            role: String::new(),
+        }))
+    }
+
+    /// Query the focused window again, the cache is cleared on failure.
+    fn refresh(&mut self) {
+        self.focused = match self.query_focused() {
+            Ok(focused) => focused,
+            Err(e) => {
+                debug!("Failed to get the focused window: {}", e);
+                None
+            }
+        };
+        debug!("Focused window => {:?}", self.focused);
+    }
+
+    /// Read all available bytes, false once the connection is closed.
+    fn receive(&mut self) -> std::io::Result<bool> {
+        let mut buf = [0u8; 4096];
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => return Ok(false),
+                Ok(n) => self.buffer.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(true),
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn reconnect(&mut self) -> Result<(), Box<dyn Error>> {
+        self.stream = Self::connect(&self.event_path)?;
+        self.buffer.clear();
+        self.lost = None;
+        self.refresh();
+        Ok(())
+    }
+}
+
+impl WindowProvider for HyprlandClient {
+    fn fd(&self) -> Option<RawFd> {
+        if self.lost.is_some() {
+            return None;
+        }
+        Some(self.stream.as_raw_fd())
+    }
+
+    fn focused(&self) -> Option<&FocusedWindow> {
+        self.focused.as_ref()
+    }
+
+    fn retry_deadline(&self) -> Option<Instant> {
+        self.lost.map(|x| x.deadline)
+    }
+
+    fn retry(&mut self) {
+        let Some(mut lost) = self.lost else {
+            return;
+        };
+        match self.reconnect() {
+            Ok(()) => info!("Reconnected to Hyprland"),
+            Err(e) => {
+                debug!("Failed to reconnect Hyprland: {}", e);
+                lost.failed();
+                self.lost = Some(lost);
+            }
+        }
+    }
+
+    /// Process the received events, reconnect if Hyprland closed the connection.
+    fn handle_events(&mut self) {
+        let alive = match self.receive() {
+            Ok(alive) => alive,
+            Err(e) => {
+                warn!("Failed to read from Hyprland: {}", e);
+                false
+            }
+        };
+        let mut changed = false;
+        while let Some(end) = self.buffer.iter().position(|&x| x == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            let event = line.split(|&x| x == b'>').next().unwrap_or_default();
+            changed |= matches!(event, b"activewindow" | b"windowtitle" | b"closewindow");
+        }
+        if !alive {
+            warn!("Lost the Hyprland connection, reconnecting");
+            if let Err(e) = self.reconnect() {
+                warn!(
+                    "Failed to reconnect Hyprland: {}, the focused window is unknown for now",
+                    e
+                );
+                self.lost = Some(Retry::new());
+                self.focused = None;
+            }
+            return;
+        }
+        if changed {
+            self.refresh();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixListener;
+    use std::sync::mpsc::{self, Receiver};
+    use std::thread;
+
+    use super::*;
+
+    /// A fake Hyprland answering `j/activewindow` with `replies` in order.
+    /// Returns the client, the server side of the event socket, and the requests received.
+    fn connect(
+        replies: &[&str],
+    ) -> (
+        HyprlandClient,
+        UnixStream,
+        Receiver<Vec<u8>>,
+        tempfile::TempDir,
+    ) {
+        let dir = tempfile::tempdir().unwrap();
+        let event_path = dir.path().join(".socket2.sock");
+        let request_path = dir.path().join(".socket.sock");
+        let events = UnixListener::bind(&event_path).unwrap();
+        let requests = UnixListener::bind(&request_path).unwrap();
+
+        let (sender, received) = mpsc::channel();
+        let replies: Vec<String> = replies.iter().map(|x| x.to_string()).collect();
+        thread::spawn(move || {
+            for reply in replies {
+                let (mut stream, _) = requests.accept().unwrap();
+                let mut buf = [0u8; 256];
+                let n = stream.read(&mut buf).unwrap();
+                sender.send(buf[..n].to_vec()).unwrap();
+                stream.write_all(reply.as_bytes()).unwrap();
+            }
+        });
+
+        let client = HyprlandClient::new(&event_path, &request_path).unwrap();
+        let (server, _) = events.accept().unwrap();
+        (client, server, received, dir)
+    }
+
+    fn class(client: &HyprlandClient) -> Option<&str> {
+        client.focused().map(|x| x.class.as_str())
+    }
+
+    #[test]
+    fn events_query_the_focused_window() {
+        let kitty = r#"{"class":"kitty","title":"~","initialClass":"kitty"}"#;
+        let firefox = r#"{"class":"firefox","title":"Hyprland","initialClass":"Firefox"}"#;
+        let (mut client, mut server, received, _dir) = connect(&[kitty, firefox, "{}"]);
+        assert_eq!(received.recv().unwrap(), b"j/activewindow");
+        assert_eq!(class(&client), Some("kitty"));
+
+        server
+            .write_all(b"activewindow>>firefox,Hyprland\n")
+            .unwrap();
+        client.handle_events();
+        assert_eq!(received.recv().unwrap(), b"j/activewindow");
+        let focused = client.focused().unwrap();
+        assert_eq!(focused.class, "firefox");
+        assert_eq!(focused.instance, "Firefox");
+        assert_eq!(focused.title, "Hyprland");
+
+        server.write_all(b"closewindow>>5678\n").unwrap();
+        client.handle_events();
+        assert_eq!(received.recv().unwrap(), b"j/activewindow");
+        assert_eq!(class(&client), None);
+    }
+
+    #[test]
+    fn other_events_and_partial_lines_wait() {
+        let kitty = r#"{"class":"kitty","title":"~","initialClass":"kitty"}"#;
+        let firefox = r#"{"class":"firefox","title":"Hyprland","initialClass":"firefox"}"#;
+        let (mut client, mut server, received, _dir) = connect(&[kitty, firefox]);
+        received.recv().unwrap();
+
+        // Neither an unrelated event nor half a line asks Hyprland.
+        server.write_all(b"workspace>>2\nactivewin").unwrap();
+        client.handle_events();
+        assert!(received.try_recv().is_err());
+        assert_eq!(class(&client), Some("kitty"));
+
+        server.write_all(b"dow>>firefox,Hyprland\n").unwrap();
+        client.handle_events();
+        assert_eq!(received.recv().unwrap(), b"j/activewindow");
+        assert_eq!(class(&client), Some("firefox"));
+    }
+}
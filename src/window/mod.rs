@@ -0,0 +1,119 @@
+mod hyprland;
+mod sway;
+mod x11;
+
+use std::error::Error;
+use std::os::fd::RawFd;
+use std::time::{Duration, Instant};
+
+use log::{debug, info};
+
+pub use hyprland::HyprlandClient;
+pub use sway::SwayClient;
+pub use x11::X11Client;
+
+/// What we know about the focused window.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct FocusedWindow {
+    pub class: String,
+    pub instance: String,
+    pub title: String,
+    pub role: String,
+}
+
+/// The wait before reconnecting a lost connection, doubled after each failure up to the max.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// When to try connecting again after the connection is lost.
+#[derive(Debug, Clone, Copy)]
+pub struct Retry {
+    pub deadline: Instant,
+    delay: Duration,
+}
+
+impl Retry {
+    pub fn new() -> Self {
+        Self {
+            deadline: Instant::now() + RETRY_DELAY,
+            delay: RETRY_DELAY,
+        }
+    }
+
+    /// Another try failed, wait longer.
+    pub fn failed(&mut self) {
+        self.delay = (self.delay * 2).min(MAX_RETRY_DELAY);
+        self.deadline = Instant::now() + self.delay;
+    }
+}
+
+/// Tells the focused window, kept up to date from the events of a display server.
+pub trait WindowProvider {
+    /// The connection to select on, `handle_events` once readable.
+    /// None if there is nothing to wait for, e.g. the connection is lost.
+    fn fd(&self) -> Option<RawFd>;
+
+    /// Process the pending events without blocking.
+    fn handle_events(&mut self);
+
+    /// The focused window as of the last event, None if unknown.
+    fn focused(&self) -> Option<&FocusedWindow>;
+
+    /// When to `retry` the lost connection, None while connected.
+    fn retry_deadline(&self) -> Option<Instant>;
+
+    /// Connect again if the connection is lost.
+    fn retry(&mut self);
+}
+
+/// The configured keyboard layouts, e.g. `us` or `de,us`, from `$XKB_DEFAULT_LAYOUT`, or the
+/// X server if `use_display` and not under sway or Hyprland. None if unknown.
+pub fn keyboard_layout(use_display: bool) -> Option<String> {
+    if let Ok(layout) = std::env::var("XKB_DEFAULT_LAYOUT")
+        && !layout.is_empty()
+    {
+        return Some(layout);
+    }
+    let wayland = std::env::var_os("SWAYSOCK").is_some()
+        || std::env::var_os("HYPRLAND_INSTANCE_SIGNATURE").is_some();
+    if !use_display || wayland || std::env::var_os("DISPLAY").is_none() {
+        return None;
+    }
+    match x11::keyboard_layout() {
+        Ok(layout) => layout,
+        Err(e) => {
+            debug!("Failed to get the keyboard layout: {}", e);
+            None
+        }
+    }
+}
+
+/// Pick the provider of the running session: sway, Hyprland, or X11.
+pub fn connect() -> Result<Box<dyn WindowProvider>, Box<dyn Error>> {
+    if let Some(path) = std::env::var_os("SWAYSOCK") {
+        info!("Using sway IPC for the focused window");
+        return Ok(Box::new(SwayClient::new(path.as_ref())?));
+    }
+    if let Some(signature) = std::env::var_os("HYPRLAND_INSTANCE_SIGNATURE") {
+        info!("Using Hyprland IPC for the focused window");
+        let dir = hyprland::socket_dir(signature.as_ref());
+        return Ok(Box::new(HyprlandClient::new(
+            &dir.join(".socket2.sock"),
+            &dir.join(".socket.sock"),
+        )?));
+    }
+
+    let mut retry_count = 3;
+    loop {
+        match X11Client::new() {
+            Ok(client) => return Ok(Box::new(client)),
+            Err(e) => {
+                if retry_count == 0 {
+                    return Err(e);
+                }
+            }
+        }
+        retry_count -= 1;
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,470 @@
+use std::error::Error;
+use std::io::{ErrorKind, Read, Write};
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+use serde::Deserialize;
+
+use super::{FocusedWindow, Retry, WindowProvider};
+
+// i3/sway IPC, both ways a message is
+//
+//   "i3-ipc" <payload length: u32> <type: u32> <JSON payload>
+//
+// in native byte order. Events have the highest bit of the type set.
+
+const MAGIC: &[u8] = b"i3-ipc";
+const HEADER_LEN: usize = MAGIC.len() + 8;
+const SUBSCRIBE: u32 = 2;
+const GET_TREE: u32 = 4;
+const WORKSPACE_EVENT: u32 = 0x8000_0000;
+const WINDOW_EVENT: u32 = 0x8000_0003;
+
+/// The replies at startup must not block for long.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A node of the sway tree, only the fields we use.
+#[derive(Debug, Deserialize)]
+struct Node {
+    id: i64,
+    #[serde(default)]
+    focused: bool,
+    name: Option<String>,
+    /// Native Wayland windows have this
+    app_id: Option<String>,
+    /// XWayland windows have this
+    window_properties: Option<WindowProperties>,
+    #[serde(default)]
+    nodes: Vec<Node>,
+    #[serde(default)]
+    floating_nodes: Vec<Node>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct WindowProperties {
+    class: Option<String>,
+    instance: Option<String>,
+    window_role: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct WindowEvent {
+    change: String,
+    container: Node,
+}
+
+#[derive(Debug, Deserialize)]
+struct WorkspaceEvent {
+    change: String,
+    current: Option<Node>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CommandReply {
+    success: bool,
+}
+
+impl Node {
+    /// The focused window in this subtree.
+    fn find_focused(&self) -> Option<&Node> {
+        if self.focused {
+            return Some(self);
+        }
+        self.nodes
+            .iter()
+            .chain(self.floating_nodes.iter())
+            .find_map(|x| x.find_focused())
+    }
+
+    /// None if the node isn't a window, e.g. an empty workspace.
+    fn to_window(&self) -> Option<FocusedWindow> {
+        if self.app_id.is_none() && self.window_properties.is_none() {
+            return None;
+        }
+        let properties = self.window_properties.as_ref();
+        let app_id = self.app_id.clone().unwrap_or_default();
+        Some(FocusedWindow {
+            class: properties
+                .and_then(|x| x.class.clone())
+                .unwrap_or_else(|| app_id.clone()),
+            instance: properties
+                .and_then(|x| x.instance.clone())
+                .unwrap_or(app_id),
+            title: self.name.clone().unwrap_or_default(),
+            role: properties
+                .and_then(|x| x.window_role.clone())
+                .unwrap_or_default(),
+        })
+    }
+}
+
+/// Tracks the focused window through the window events of sway IPC. For XWayland windows the
+/// X11 class and instance are used, for native ones both are the `app_id`.
+pub struct SwayClient {
+    path: PathBuf,
+    stream: UnixStream,
+    /// Received bytes not making a whole message yet
+    buffer: Vec<u8>,
+    focused_id: Option<i64>,
+    focused: Option<FocusedWindow>,
+    /// Set once the connection is broken and reconnecting failed
+    lost: Option<Retry>,
+}
+
+impl SwayClient {
+    /// Connect to the IPC socket at `path`, usually `$SWAYSOCK`.
+    pub fn new(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let (stream, focused_id, focused) = Self::connect(path)?;
+        let mut client = Self {
+            path: path.to_path_buf(),
+            stream,
+            buffer: vec![],
+            focused_id: None,
+            focused: None,
+            lost: None,
+        };
+        client.set_focused(focused_id, focused);
+        Ok(client)
+    }
+
+    /// Get the tree for the current focus, then subscribe to the changes.
+    #[allow(clippy::type_complexity)]
+    fn connect(
+        path: &Path,
+    ) -> Result<(UnixStream, Option<i64>, Option<FocusedWindow>), Box<dyn Error>> {
+        let mut stream = UnixStream::connect(path)
+            .map_err(|e| format!("Failed to connect to sway at {}: {}", path.display(), e))?;
+        stream.set_read_timeout(Some(REPLY_TIMEOUT))?;
+
+        send_message(&mut stream, GET_TREE, b"")?;
+        let tree: Node = serde_json::from_slice(&read_reply(&mut stream, GET_TREE)?)?;
+        let focused = tree.find_focused();
+
+        send_message(&mut stream, SUBSCRIBE, br#"["window","workspace"]"#)?;
+        let reply: CommandReply = serde_json::from_slice(&read_reply(&mut stream, SUBSCRIBE)?)?;
+        if !reply.success {
+            return Err("sway refused the subscription".into());
+        }
+        stream.set_nonblocking(true)?;
+
+        Ok((
+            stream,
+            focused.map(|x| x.id),
+            focused.and_then(|x| x.to_window()),
+        ))
+    }
+
+    fn set_focused(&mut self, id: Option<i64>, window: Option<FocusedWindow>) {
+        self.focused_id = id;
+        self.focused = window;
+        debug!("Focused window => {:?}", self.focused);
+    }
+
+    fn handle_message(&mut self, type_: u32, payload: &[u8]) -> Result<(), Box<dyn Error>> {
+        match type_ {
+            WINDOW_EVENT => {
+                let event: WindowEvent = serde_json::from_slice(payload)?;
+                let container = event.container;
+                let is_focused = Some(container.id) == self.focused_id;
+                match event.change.as_str() {
+                    "focus" => self.set_focused(Some(container.id), container.to_window()),
+                    "title" if is_focused => {
+                        self.set_focused(Some(container.id), container.to_window())
+                    }
+                    "close" if is_focused => self.set_focused(None, None),
+                    _ => {}
+                }
+            }
+            WORKSPACE_EVENT => {
+                // Switching to an empty workspace focuses no window, sway sends no window event.
+                let event: WorkspaceEvent = serde_json::from_slice(payload)?;
+                if event.change == "focus"
+                    && let Some(current) = event.current
+                    && current.find_focused().is_none_or(|x| x.id == current.id)
+                {
+                    self.set_focused(None, None);
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Read all available bytes, false once the connection is closed.
+    fn receive(&mut self) -> std::io::Result<bool> {
+        let mut buf = [0u8; 4096];
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => return Ok(false),
+                Ok(n) => self.buffer.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(true),
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn reconnect(&mut self) -> Result<(), Box<dyn Error>> {
+        let (stream, focused_id, focused) = Self::connect(&self.path)?;
+        self.stream = stream;
+        self.buffer.clear();
+        self.lost = None;
+        self.set_focused(focused_id, focused);
+        Ok(())
+    }
+}
+
+impl WindowProvider for SwayClient {
+    fn fd(&self) -> Option<RawFd> {
+        if self.lost.is_some() {
+            return None;
+        }
+        Some(self.stream.as_raw_fd())
+    }
+
+    fn focused(&self) -> Option<&FocusedWindow> {
+        self.focused.as_ref()
+    }
+
+    fn retry_deadline(&self) -> Option<Instant> {
+        self.lost.map(|x| x.deadline)
+    }
+
+    fn retry(&mut self) {
+        let Some(mut lost) = self.lost else {
+            return;
+        };
+        match self.reconnect() {
+            Ok(()) => info!("Reconnected to sway"),
+            Err(e) => {
+                debug!("Failed to reconnect sway: {}", e);
+                lost.failed();
+                self.lost = Some(lost);
+            }
+        }
+    }
+
+    /// Process the received events, reconnect if sway closed the connection.
+    fn handle_events(&mut self) {
+        let alive = match self.receive() {
+            Ok(alive) => alive,
+            Err(e) => {
+                warn!("Failed to read from sway: {}", e);
+                false
+            }
+        };
+        while let Some((type_, payload)) = take_message(&mut self.buffer) {
+            if let Err(e) = self.handle_message(type_, &payload) {
+                debug!("Failed to parse a sway event: {}", e);
+            }
+        }
+        if alive {
+            return;
+        }
+        warn!("Lost the sway connection, reconnecting");
+        if let Err(e) = self.reconnect() {
+            warn!(
+                "Failed to reconnect sway: {}, the focused window is unknown for now",
+                e
+            );
+            self.lost = Some(Retry::new());
+            self.set_focused(None, None);
+        }
+    }
+}
+
+fn send_message(stream: &mut UnixStream, type_: u32, payload: &[u8]) -> std::io::Result<()> {
+    let mut message = Vec::with_capacity(HEADER_LEN + payload.len());
+    message.extend_from_slice(MAGIC);
+    message.extend_from_slice(&(payload.len() as u32).to_ne_bytes());
+    message.extend_from_slice(&type_.to_ne_bytes());
+    message.extend_from_slice(payload);
+    stream.write_all(&message)
+}
+
+/// Block for the reply of the given type, events before it are dropped.
+fn read_reply(stream: &mut UnixStream, type_: u32) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut buffer = vec![];
+    let mut buf = [0u8; 4096];
+    loop {
+        while let Some((t, payload)) = take_message(&mut buffer) {
+            if t == type_ {
+                return Ok(payload);
+            }
+        }
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            return Err("sway closed the connection".into());
+        }
+        buffer.extend_from_slice(&buf[..n]);
+    }
+}
+
+/// Split the first whole message off the buffer.
+fn take_message(buffer: &mut Vec<u8>) -> Option<(u32, Vec<u8>)> {
+    if buffer.len() < HEADER_LEN {
+        return None;
+    }
+    let len = u32::from_ne_bytes(buffer[6..10].try_into().unwrap()) as usize;
+    let type_ = u32::from_ne_bytes(buffer[10..14].try_into().unwrap());
+    if buffer.len() < HEADER_LEN + len {
+        return None;
+    }
+    let payload = buffer[HEADER_LEN..HEADER_LEN + len].to_vec();
+    buffer.drain(..HEADER_LEN + len);
+    Some((type_, payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+
+    use super::*;
+
+    /// Start a fake sway at `path` that answers GET_TREE with `tree` and accepts the
+    /// subscription of one client. Returns the server side to send events on.
+    fn serve(path: &Path, tree: &str) -> thread::JoinHandle<UnixStream> {
+        std::fs::remove_file(path).ok();
+        let listener = UnixListener::bind(path).unwrap();
+        let tree = tree.to_string();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            read_reply(&mut stream, GET_TREE).unwrap();
+            send_message(&mut stream, GET_TREE, tree.as_bytes()).unwrap();
+            let events = read_reply(&mut stream, SUBSCRIBE).unwrap();
+            assert_eq!(events, br#"["window","workspace"]"#);
+            send_message(&mut stream, SUBSCRIBE, br#"{"success":true}"#).unwrap();
+            stream
+        })
+    }
+
+    /// Connect to a fake sway. Returns the client and the server side to send events on.
+    fn connect(tree: &str) -> (SwayClient, UnixStream, tempfile::TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sway.sock");
+        let server = serve(&path, tree);
+        let client = SwayClient::new(&path).unwrap();
+        (client, server.join().unwrap(), dir)
+    }
+
+    fn class(client: &SwayClient) -> Option<&str> {
+        client.focused().map(|x| x.class.as_str())
+    }
+
+    const EMPTY_TREE: &str = r#"{"id":1,"nodes":[]}"#;
+
+    #[test]
+    fn focus_from_tree() {
+        let tree = r#"{"id":1,"nodes":[{"id":2,"nodes":[
+            {"id":3,"name":"foot","app_id":"foot"},
+            {"id":4,"focused":true,"name":"Untitled - GIMP",
+             "window_properties":{"class":"Gimp","instance":"gimp","window_role":"gimp-image-window"}}
+        ]}]}"#;
+        let (client, _server, _dir) = connect(tree);
+        let focused = client.focused().unwrap();
+        assert_eq!(focused.class, "Gimp");
+        assert_eq!(focused.instance, "gimp");
+        assert_eq!(focused.title, "Untitled - GIMP");
+        assert_eq!(focused.role, "gimp-image-window");
+    }
+
+    #[test]
+    fn window_events() {
+        let (mut client, mut server, _dir) = connect(EMPTY_TREE);
+        assert_eq!(class(&client), None);
+
+        let focus = r#"{"change":"focus","container":{"id":5,"name":"vim","app_id":"foot"}}"#;
+        send_message(&mut server, WINDOW_EVENT, focus.as_bytes()).unwrap();
+        client.handle_events();
+        assert_eq!(class(&client), Some("foot"));
+
+        // Title changes of other windows are ignored.
+        let other = r#"{"change":"title","container":{"id":6,"name":"x","app_id":"kitty"}}"#;
+        let title = r#"{"change":"title","container":{"id":5,"name":"emacs","app_id":"foot"}}"#;
+        send_message(&mut server, WINDOW_EVENT, other.as_bytes()).unwrap();
+        send_message(&mut server, WINDOW_EVENT, title.as_bytes()).unwrap();
+        client.handle_events();
+        assert_eq!(client.focused().unwrap().title, "emacs");
+        assert_eq!(class(&client), Some("foot"));
+
+        let close = r#"{"change":"close","container":{"id":5,"app_id":"foot"}}"#;
+        send_message(&mut server, WINDOW_EVENT, close.as_bytes()).unwrap();
+        client.handle_events();
+        assert_eq!(class(&client), None);
+    }
+
+    #[test]
+    fn empty_workspace_focus() {
+        let (mut client, mut server, _dir) = connect(EMPTY_TREE);
+        let focus = r#"{"change":"focus","container":{"id":5,"app_id":"foot"}}"#;
+        send_message(&mut server, WINDOW_EVENT, focus.as_bytes()).unwrap();
+        client.handle_events();
+        assert_eq!(class(&client), Some("foot"));
+
+        let workspace = r#"{"change":"focus","current":{"id":9,"focused":true,"nodes":[]}}"#;
+        send_message(&mut server, WORKSPACE_EVENT, workspace.as_bytes()).unwrap();
+        client.handle_events();
+        assert_eq!(class(&client), None);
+    }
+
+    #[test]
+    fn message_split_across_reads() {
+        let (mut client, mut server, _dir) = connect(EMPTY_TREE);
+        let focus = r#"{"change":"focus","container":{"id":5,"app_id":"foot"}}"#;
+        let mut message = vec![];
+        message.extend_from_slice(MAGIC);
+        message.extend_from_slice(&(focus.len() as u32).to_ne_bytes());
+        message.extend_from_slice(&WINDOW_EVENT.to_ne_bytes());
+        message.extend_from_slice(focus.as_bytes());
+
+        // Half of the header, then the rest.
+        server.write_all(&message[..7]).unwrap();
+        client.handle_events();
+        assert_eq!(class(&client), None);
+        server.write_all(&message[7..]).unwrap();
+        client.handle_events();
+        assert_eq!(class(&client), Some("foot"));
+    }
+
+    #[test]
+    fn retry_after_lost_connection() {
+        let (mut client, server, dir) = connect(EMPTY_TREE);
+        assert!(client.retry_deadline().is_none());
+
+        // sway is gone and can't be reconnected right away.
+        drop(server);
+        client.handle_events();
+        assert!(client.fd().is_none());
+        let first = client.retry_deadline().unwrap();
+        client.retry();
+        assert!(client.retry_deadline().unwrap() > first);
+
+        let tree = r#"{"id":1,"nodes":[{"id":2,"focused":true,"app_id":"foot"}]}"#;
+        let server = serve(&dir.path().join("sway.sock"), tree);
+        client.retry();
+        let _server = server.join().unwrap();
+        assert!(client.fd().is_some());
+        assert!(client.retry_deadline().is_none());
+        assert_eq!(class(&client), Some("foot"));
+    }
+
+    #[test]
+    fn take_message_waits_for_whole_message() {
+        let mut buffer = vec![];
+        buffer.extend_from_slice(MAGIC);
+        buffer.extend_from_slice(&3u32.to_ne_bytes());
+        buffer.extend_from_slice(&WINDOW_EVENT.to_ne_bytes());
+        buffer.extend_from_slice(b"{}");
+        assert_eq!(take_message(&mut buffer), None);
+        buffer.extend_from_slice(b" ");
+        assert_eq!(
+            take_message(&mut buffer),
+            Some((WINDOW_EVENT, b"{} ".to_vec()))
+        );
+        assert!(buffer.is_empty());
+    }
+}
@@ -0,0 +1,267 @@
+use std::error;
+use std::os::fd::{AsRawFd, RawFd};
+use std::time::Instant;
+
+use log::{debug, info, warn};
+use x11rb::connection::Connection;
+use x11rb::properties::WmClass;
+use x11rb::protocol::Event;
+use x11rb::protocol::xproto::{
+    AtomEnum, ChangeWindowAttributesAux, ConnectionExt, EventMask, Window,
+};
+use x11rb::rust_connection::RustConnection;
+
+use super::{FocusedWindow, Retry, WindowProvider};
+
+/// Atoms interned once per connection.
+#[derive(Debug, Clone, Copy)]
+struct Atoms {
+    net_active_window: u32,
+    net_wm_name: u32,
+    utf8_string: u32,
+    wm_window_role: u32,
+}
+
+/// Tracks the focused window through `_NET_ACTIVE_WINDOW` on the root window, so that
+/// lookups are served from the cache instead of a round trip per key press.
+pub struct X11Client {
+    conn: RustConnection,
+    root: Window,
+    atoms: Atoms,
+    /// The window whose title changes we listen to
+    window: Option<Window>,
+    focused: Option<FocusedWindow>,
+    /// Set once the connection is broken and reconnecting failed
+    lost: Option<Retry>,
+}
+
+impl X11Client {
+    pub fn new() -> Result<Self, Box<dyn error::Error>> {
+        let (conn, root, atoms) = Self::connect()?;
+        let mut client = Self {
+            conn,
+            root,
+            atoms,
+            window: None,
+            focused: None,
+            lost: None,
+        };
+        client.refresh();
+        Ok(client)
+    }
+
+    fn connect() -> Result<(RustConnection, Window, Atoms), Box<dyn error::Error>> {
+        let dpy_name: Option<&str> = None;
+        let (conn, screen_num) = x11rb::connect(dpy_name)?;
+        let root = conn.setup().roots[screen_num].root;
+        let net_active_window = conn.intern_atom(false, b"_NET_ACTIVE_WINDOW")?;
+        let net_wm_name = conn.intern_atom(false, b"_NET_WM_NAME")?;
+        let utf8_string = conn.intern_atom(false, b"UTF8_STRING")?;
+        let wm_window_role = conn.intern_atom(false, b"WM_WINDOW_ROLE")?;
+        let atoms = Atoms {
+            net_active_window: net_active_window.reply()?.atom,
+            net_wm_name: net_wm_name.reply()?.atom,
+            utf8_string: utf8_string.reply()?.atom,
+            wm_window_role: wm_window_role.reply()?.atom,
+        };
+
+        // Get PropertyNotify when the window manager changes the active window.
+        let aux = ChangeWindowAttributesAux::new().event_mask(EventMask::PROPERTY_CHANGE);
+        conn.change_window_attributes(root, &aux)?.check()?;
+        conn.flush()?;
+        Ok((conn, root, atoms))
+    }
+
+    /// The active window changed, or the title of the active window did.
+    fn is_relevant(&self, window: Window, atom: u32) -> bool {
+        if window == self.root {
+            return atom == self.atoms.net_active_window;
+        }
+        Some(window) == self.window
+            && (atom == self.atoms.net_wm_name || atom == u32::from(AtomEnum::WM_NAME))
+    }
+
+    /// Query the focused window again, the cache is cleared on failure.
+    fn refresh(&mut self) {
+        if let Ok(window) = self.active_window()
+            && Some(window) != self.window
+        {
+            self.watch(window);
+        }
+        self.focused = match self.query_focused() {
+            Ok(focused) => Some(focused),
+            Err(e) => {
+                debug!("Failed to get the focused window: {}", e);
+                None
+            }
+        };
+        debug!("Focused window => {:?}", self.focused);
+    }
+
+    /// Listen to the title changes of the newly active window instead of the previous one.
+    fn watch(&mut self, window: Window) {
+        if let Some(old) = self.window.take() {
+            // The old window may be gone already.
+            let aux = ChangeWindowAttributesAux::new().event_mask(EventMask::NO_EVENT);
+            let _ = self.conn.change_window_attributes(old, &aux);
+        }
+        if window == x11rb::NONE || window == self.root {
+            return;
+        }
+        let aux = ChangeWindowAttributesAux::new().event_mask(EventMask::PROPERTY_CHANGE);
+        if self.conn.change_window_attributes(window, &aux).is_ok() {
+            self.window = Some(window);
+        }
+        let _ = self.conn.flush();
+    }
+
+    fn query_focused(&self) -> Result<FocusedWindow, Box<dyn error::Error>> {
+        let window = self.window.ok_or("No active window")?;
+        let wm_class = WmClass::get(&self.conn, window)?
+            .reply()?
+            .ok_or("No WM_CLASS")?;
+        let mut title =
+            self.text_property(window, self.atoms.net_wm_name, self.atoms.utf8_string)?;
+        if title.is_empty() {
+            title = self.text_property(window, AtomEnum::WM_NAME.into(), AtomEnum::ANY.into())?;
+        }
+        let role =
+            self.text_property(window, self.atoms.wm_window_role, AtomEnum::STRING.into())?;
+        Ok(FocusedWindow {
+            class: String::from_utf8_lossy(wm_class.class()).to_string(),
+            instance: String::from_utf8_lossy(wm_class.instance()).to_string(),
+            title,
+            role,
+        })
+    }
+
+    /// A string property, empty if unset.
+    fn text_property(
+        &self,
+        window: Window,
+        property: u32,
+        type_: u32,
+    ) -> Result<String, Box<dyn error::Error>> {
+        let reply = self
+            .conn
+            .get_property(false, window, property, type_, 0, u32::MAX)?
+            .reply()?;
+        Ok(String::from_utf8_lossy(&reply.value).to_string())
+    }
+
+    /// `_NET_ACTIVE_WINDOW`, or the input focus if the window manager doesn't set it.
+    fn active_window(&self) -> Result<Window, Box<dyn error::Error>> {
+        let reply = self
+            .conn
+            .get_property(
+                false,
+                self.root,
+                self.atoms.net_active_window,
+                AtomEnum::WINDOW,
+                0,
+                1,
+            )?
+            .reply()?;
+        if let Some(window) = reply.value32().and_then(|mut x| x.next())
+            && window != x11rb::NONE
+        {
+            return Ok(window);
+        }
+        Ok(self.conn.get_input_focus()?.reply()?.focus)
+    }
+
+    fn reconnect(&mut self) -> Result<(), Box<dyn error::Error>> {
+        let (conn, root, atoms) = Self::connect()?;
+        self.conn = conn;
+        self.root = root;
+        self.atoms = atoms;
+        self.window = None;
+        self.lost = None;
+        self.refresh();
+        Ok(())
+    }
+}
+
+impl WindowProvider for X11Client {
+    fn fd(&self) -> Option<RawFd> {
+        if self.lost.is_some() {
+            return None;
+        }
+        Some(self.conn.stream().as_raw_fd())
+    }
+
+    fn focused(&self) -> Option<&FocusedWindow> {
+        self.focused.as_ref()
+    }
+
+    fn retry_deadline(&self) -> Option<Instant> {
+        self.lost.map(|x| x.deadline)
+    }
+
+    fn retry(&mut self) {
+        let Some(mut lost) = self.lost else {
+            return;
+        };
+        match self.reconnect() {
+            Ok(()) => info!("Reconnected to X"),
+            Err(e) => {
+                debug!("Failed to reconnect X: {}", e);
+                lost.failed();
+                self.lost = Some(lost);
+            }
+        }
+    }
+
+    /// Process the pending X events, reconnect if the connection is broken.
+    fn handle_events(&mut self) {
+        let mut changed = false;
+        loop {
+            match self.conn.poll_for_event() {
+                Ok(Some(Event::PropertyNotify(e))) if self.is_relevant(e.window, e.atom) => {
+                    changed = true;
+                }
+                Ok(Some(_)) => {}
+                // The replies of `refresh` may bring more events into the queue,
+                // and the fd won't tell about those.
+                Ok(None) if changed => {
+                    changed = false;
+                    self.refresh();
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("Lost the X connection: {}, reconnecting", e);
+                    if let Err(e) = self.reconnect() {
+                        warn!(
+                            "Failed to reconnect X: {}, the focused window is unknown for now",
+                            e
+                        );
+                        self.lost = Some(Retry::new());
+                        self.focused = None;
+                    }
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// The layouts of `_XKB_RULES_NAMES` on the root window, which holds the rules, model, layouts,
+/// variants and options separated by NUL.
+pub fn keyboard_layout() -> Result<Option<String>, Box<dyn error::Error>> {
+    let dpy_name: Option<&str> = None;
+    let (conn, screen_num) = x11rb::connect(dpy_name)?;
+    let root = conn.setup().roots[screen_num].root;
+    let rules_names = conn.intern_atom(true, b"_XKB_RULES_NAMES")?.reply()?.atom;
+    if rules_names == x11rb::NONE {
+        return Ok(None);
+    }
+    let reply = conn
+        .get_property(false, root, rules_names, AtomEnum::STRING, 0, 1024)?
+        .reply()?;
+    Ok(reply
+        .value
+        .split(|&x| x == 0)
+        .nth(2)
+        .filter(|x| !x.is_empty())
+        .map(|x| String::from_utf8_lossy(x).to_string()))
+}
@@ -1,3 +0,0 @@
-mod client;
-
-pub use client::*;